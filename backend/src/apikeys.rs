@@ -0,0 +1,287 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use chrono::{TimeZone, Utc};
+use parking_lot::RwLock;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use tmelcrypt::HashVal;
+use warp::{filters::path::FullPath, reject::Reject, Filter, Rejection};
+
+use crate::{export::is_export, globals::API_KEYS, localdb::LocalDb};
+
+/// Something an API key is allowed to do.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Use the public API with the bigger API key rate limits.
+    Read,
+    /// Download CSV and NDJSON exports.
+    Export,
+    /// Manage watches and read their delivery logs.
+    Watch,
+    /// Use the admin API. Implies every other scope.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Export => "export",
+            Scope::Watch => "watch",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read" => Some(Scope::Read),
+            "export" => Some(Scope::Export),
+            "watch" => Some(Scope::Watch),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A registered API key. The key itself is only ever shown when it is created; only its hash is stored.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    pub created: chrono::DateTime<Utc>,
+}
+
+impl ApiKey {
+    /// Whether the key is allowed to do something.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// A newly created API key, along with the secret that the client has to send.
+#[derive(Serialize, Clone, Debug)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub info: ApiKey,
+    pub key: String,
+}
+
+/// The API key store. Keys are persisted in the local database and mirrored in memory, so that checking a request never touches the disk.
+#[derive(Clone)]
+pub struct ApiKeys {
+    db: LocalDb,
+    keys: Arc<RwLock<HashMap<HashVal, ApiKey>>>,
+    /// Admin keys given on the command line. These are never stored.
+    bootstrap: Arc<HashMap<HashVal, ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Creates a key store backed by the given database, creating the table if needed and loading the stored keys.
+    pub fn new(db: LocalDb, admin_keys: &[String]) -> anyhow::Result<Self> {
+        let keys = db.run_blocking(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS api_keys (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    key_hash TEXT NOT NULL UNIQUE,
+                    name TEXT NOT NULL,
+                    scopes TEXT NOT NULL,
+                    created INTEGER NOT NULL
+                );",
+            )?;
+            let mut stmt =
+                conn.prepare("SELECT id, key_hash, name, scopes, created FROM api_keys")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+            let mut keys = HashMap::new();
+            for row in rows {
+                let (id, key_hash, name, scopes, created) = row?;
+                keys.insert(
+                    key_hash.parse()?,
+                    ApiKey {
+                        id: id as u64,
+                        name,
+                        scopes: scopes.split(',').filter_map(Scope::parse).collect(),
+                        created: Utc
+                            .timestamp_opt(created, 0)
+                            .single()
+                            .unwrap_or_else(Utc::now),
+                    },
+                );
+            }
+            Ok(keys)
+        })?;
+        let bootstrap = admin_keys
+            .iter()
+            .map(|key| {
+                (
+                    hash_key(key),
+                    ApiKey {
+                        id: 0,
+                        name: "command line".into(),
+                        scopes: std::iter::once(Scope::Admin).collect(),
+                        created: Utc::now(),
+                    },
+                )
+            })
+            .collect();
+        Ok(Self {
+            db,
+            keys: Arc::new(RwLock::new(keys)),
+            bootstrap: Arc::new(bootstrap),
+        })
+    }
+
+    /// Looks up the key that a client sent.
+    pub fn lookup(&self, key: &str) -> Option<ApiKey> {
+        let hash = hash_key(key);
+        if let Some(key) = self.bootstrap.get(&hash) {
+            return Some(key.clone());
+        }
+        self.keys.read().get(&hash).cloned()
+    }
+
+    /// Creates a new key with the given scopes.
+    pub async fn create(&self, name: String, scopes: BTreeSet<Scope>) -> anyhow::Result<NewApiKey> {
+        if scopes.is_empty() {
+            anyhow::bail!("an API key needs at least one scope")
+        }
+        let key = HashVal::random().to_string();
+        let hash = hash_key(&key);
+        let created = Utc::now();
+        let scopes_str = scopes
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let name_copy = name.clone();
+        let id = self
+            .db
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO api_keys (key_hash, name, scopes, created) VALUES (?1, ?2, ?3, ?4)",
+                    params![hash.to_string(), name_copy, scopes_str, created.timestamp()],
+                )?;
+                Ok(conn.last_insert_rowid() as u64)
+            })
+            .await?;
+        let info = ApiKey {
+            id,
+            name,
+            scopes,
+            created,
+        };
+        self.keys.write().insert(hash, info.clone());
+        Ok(NewApiKey { info, key })
+    }
+
+    /// Revokes a stored key, returning whether it existed.
+    pub async fn revoke(&self, id: u64) -> anyhow::Result<bool> {
+        let removed = self
+            .db
+            .run(move |conn| {
+                let n = conn.execute("DELETE FROM api_keys WHERE id = ?1", params![id as i64])?;
+                Ok(n > 0)
+            })
+            .await?;
+        self.keys.write().retain(|_, key| key.id != id);
+        Ok(removed)
+    }
+
+    /// Lists the stored keys, without the keys themselves.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys = self.keys.read().values().cloned().collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|key| key.id);
+        keys
+    }
+}
+
+fn hash_key(key: &str) -> HashVal {
+    tmelcrypt::hash_keyed(b"melscan-api-key", key.trim().as_bytes())
+}
+
+/// Rejection for requests that need an API key they do not have.
+#[derive(Debug)]
+pub struct Unauthorized {
+    pub scope: Scope,
+    /// Whether the request had a valid key at all, just without the scope.
+    pub authenticated: bool,
+}
+
+impl Reject for Unauthorized {}
+
+/// Which scope a request needs, if any. Admin routes need `admin`, watchlist routes need `watch`, and CSV and NDJSON exports need `export`.
+fn required_scope(path: &str, query: Option<&str>) -> Option<Scope> {
+    if path.starts_with("/raw/admin/") {
+        return Some(Scope::Admin);
+    }
+    if path == "/raw/watchlist" || path.starts_with("/raw/watchlist/") {
+        return Some(Scope::Watch);
+    }
+    if is_export(query.unwrap_or_default()) {
+        return Some(Scope::Export);
+    }
+    None
+}
+
+/// A filter that rejects requests lacking an API key with the scope they need.
+pub fn authorize() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(
+            |path: FullPath, query: String, api_key: Option<String>| async move {
+                let scope = match required_scope(path.as_str(), Some(&query)) {
+                    Some(scope) => scope,
+                    None => return Ok(()),
+                };
+                let key = api_key.and_then(|key| API_KEYS.lookup(&key));
+                match key {
+                    Some(key) if key.allows(scope) => Ok(()),
+                    key => Err(warp::reject::custom(Unauthorized {
+                        scope,
+                        authenticated: key.is_some(),
+                    })),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_by_route() {
+        assert_eq!(required_scope("/raw/admin/state", None), Some(Scope::Admin));
+        assert_eq!(
+            required_scope("/raw/leaderboard/MEL", Some("format=csv")),
+            Some(Scope::Export)
+        );
+        assert_eq!(
+            required_scope("/raw/leaderboard/MEL", Some("format=json")),
+            None
+        );
+        assert_eq!(
+            required_scope("/raw/leaderboard/MEL", Some("format=cs%76")),
+            Some(Scope::Export)
+        );
+        assert_eq!(
+            required_scope("/raw/watchlist/deliveries", None),
+            Some(Scope::Watch)
+        );
+        assert_eq!(required_scope("/raw/latest", None), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::Arc};
 
 use anyhow::Context;
 use chrono::Utc;
@@ -7,19 +7,34 @@ use itertools::Itertools;
 use melblkidx::{BalanceTracker, Indexer};
 use melprot::Client;
 use melstructs::{
-    Address, Block, BlockHeight, CoinDataHeight, CoinID, CoinValue, Denom, Header, PoolKey,
-    Transaction, TxHash,
+    Address, Block, BlockHeight, CoinDataHeight, CoinID, CoinValue, Denom, Header, Transaction,
+    TxHash,
 };
 use melvm::covenant_weight_from_bytes;
 use moka::sync::Cache;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use smol::{lock::Semaphore, prelude::*};
 use tap::Tap;
-use tmelcrypt::HashVal;
+use tmelcrypt::{Ed25519PK, HashVal};
 
-use crate::{graphs::height_to_datetime, utils::*};
-#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
-pub struct PoolInfoKey(PoolKey, BlockHeight);
+use crate::{
+    chainidx::{
+        ChainIndex, EpochRewards, EpochSummary, ProposerStats, Reveal, StakeRecord, TxFilter,
+        TxRecord,
+    },
+    classify::{classify_address, CovenantClass},
+    config::CacheConfig,
+    disasm::{disassemble, Disassembly},
+    graphs::{height_to_datetime, BLOCK_INTERVAL_SECS},
+    utils::*,
+};
+
+/// How many blocks are produced in a day.
+const BLOCKS_PER_DAY: u64 = 86400 / BLOCK_INTERVAL_SECS;
+
+/// How many revealing transactions are listed in a covenant summary.
+const MAX_COVENANT_REVEALS: usize = 1000;
 
 #[derive(serde::Serialize, Debug, Clone)]
 // A block summary for the homepage.
@@ -70,6 +85,15 @@ pub struct Overview {
     pub erg_per_mel: f64,
     pub sym_per_mel: f64,
     pub recent_blocks: Vec<BlockSummary>,
+    pub tip_height: BlockHeight,
+    /// Estimated average seconds between recent blocks. Headers carry no timestamps, so this comes from when blocks reached the chain index, and is only available once it has followed the tip for a while.
+    pub block_interval_secs: Option<f64>,
+    /// Transactions in the last day's worth of blocks. Only available if we have a chain index.
+    pub day_transactions: Option<u64>,
+    /// Fees paid in the last day's worth of blocks. Only available if we have a chain index.
+    pub day_fees: Option<CoinValue>,
+    /// SYM staked in the current epoch. Only available if we have a chain index.
+    pub total_staked: Option<CoinValue>,
 }
 
 /// A summary of a particular address.
@@ -77,6 +101,7 @@ pub struct Overview {
 pub struct AddressSummary {
     pub balances: BTreeMap<String, f64>,
     pub transactions: Vec<AddressTransactionSummary>,
+    pub class: CovenantClass,
 }
 
 #[derive(serde::Serialize, Clone, PartialEq, PartialOrd, Debug)]
@@ -87,42 +112,233 @@ pub struct AddressTransactionSummary {
     pub deltas: BTreeMap<String, f64>,
 }
 
+/// The exact history of an address, in micro-units.
+#[derive(Clone, Debug)]
+pub struct AddressLedger {
+    pub balances: BTreeMap<Denom, CoinValue>,
+    /// Every transaction that touched the address, oldest first.
+    pub transactions: Vec<LedgerEntry>,
+}
+
+impl AddressLedger {
+    /// Sums up every change up to and including the given height. A coin counts from the height it was created until the height it was spent.
+    pub fn balances_at(&self, height: BlockHeight) -> BTreeMap<Denom, CoinValue> {
+        let mut totals: BTreeMap<Denom, i128> = BTreeMap::new();
+        for entry in self.transactions.iter().take_while(|e| e.height <= height) {
+            for (denom, delta) in entry.deltas.iter() {
+                *totals.entry(*denom).or_default() += delta;
+            }
+        }
+        totals
+            .into_iter()
+            .filter(|(_, total)| *total > 0)
+            .map(|(denom, total)| (denom, CoinValue(total as u128)))
+            .collect()
+    }
+}
+
+/// How one transaction changed the balances of an address.
+#[derive(Clone, Debug)]
+pub struct LedgerEntry {
+    pub height: BlockHeight,
+    pub txhash: TxHash,
+    pub deltas: BTreeMap<Denom, i128>,
+}
+
+/// What an address held at some height.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct AddressBalances {
+    pub height: BlockHeight,
+    pub date: chrono::DateTime<Utc>,
+    /// Balances in micro-units. Denominations the address held none of are left out.
+    pub balances: BTreeMap<String, CoinValue>,
+}
+
+/// Which of an address's coins to list.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoinStatus {
+    Unspent,
+    Spent,
+    All,
+}
+
+/// A coin that belongs, or belonged, to an address.
+#[serde_as]
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct AddressCoin {
+    #[serde_as(as = "DisplayFromStr")]
+    pub coinid: CoinID,
+    pub value: CoinValue,
+    #[serde_as(as = "DisplayFromStr")]
+    pub denom: Denom,
+    /// Hex-encoded.
+    pub additional_data: String,
+    pub create_height: BlockHeight,
+    pub create_txhash: TxHash,
+    pub spend_height: Option<BlockHeight>,
+    pub spend_txhash: Option<TxHash>,
+}
+
+/// A page of an address's coins.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct CoinPage {
+    /// Newest first.
+    pub coins: Vec<AddressCoin>,
+    /// Passing this as the cursor, with the same filters, continues the listing.
+    pub next: Option<String>,
+}
+
+/// Everything known about a covenant that has been revealed on-chain.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct CovenantSummary {
+    pub bytes: String,
+    pub class: CovenantClass,
+    pub disassembly: Disassembly,
+    pub first_reveal: Reveal,
+    pub reveal_count: u64,
+    /// The most recent transactions that revealed this covenant, newest first.
+    pub reveals: Vec<Reveal>,
+}
+
+/// The total SYM staked during one epoch.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct EpochStake {
+    pub epoch: u64,
+    pub total_staked: CoinValue,
+    /// Number of distinct public keys with an active stake.
+    pub stakers: usize,
+}
+
+/// Everything a staker has ever staked.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct StakerHistory {
+    pub pubkey: Ed25519PK,
+    /// SYM staked in the latest indexed epoch.
+    pub active_staked: CoinValue,
+    /// Every stake the staker has made, newest first.
+    pub stakes: Vec<StakeRecord>,
+}
+
+/// Everything known about the proposer rewards received by one covenant.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ProposerSummary {
+    #[serde(flatten)]
+    pub stats: ProposerStats,
+    /// Rewards received in each epoch, oldest first. Epochs without any are skipped.
+    pub epochs: Vec<EpochRewards>,
+}
+
+/// A page of block summaries.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BlockPage {
+    pub blocks: Vec<BlockSummary>,
+    /// Where the next page starts, if there is one. Passing this as `from`, with the same `to`, continues the listing.
+    pub next: Option<BlockHeight>,
+}
+
+/// A page of transaction search results.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct TxSearchPage {
+    pub transactions: Vec<TxRecord>,
+    /// Passing this as the cursor, with the same filters, continues the search.
+    pub next: Option<String>,
+}
+
+/// What the backend currently holds, for the admin API.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BackendState {
+    pub has_indexer: bool,
+    pub indexed_highest: BlockHeight,
+    /// The height the chain index has caught up to, if there is one.
+    pub chain_indexed: Option<BlockHeight>,
+    pub address_ledgers_cached: u64,
+    pub holder_lists_cached: u64,
+    pub supply_trackers: usize,
+}
+
 /// A Backend encapsulates the current state of a blockchain and exposes methods that are convenient to call from JSON-returning APIs.
 #[derive(Clone)]
 pub struct Backend {
     pub client: Client,
 
     indexer: Option<Arc<Indexer>>,
+    chainidx: Option<ChainIndex>,
     supply_cache: Arc<DashMap<Denom, Arc<BalanceTracker>>>,
 
-    address_summary_cache: Arc<Cache<Address, AddressSummary>>,
-    leaderboard_cache: Arc<Cache<Denom, BTreeMap<String, f64>>>,
+    address_ledger_cache: Arc<Cache<Address, Arc<AddressLedger>>>,
+    address_coins_cache: Arc<Cache<Address, Arc<Vec<AddressCoin>>>>,
+    covenant_class_cache: Arc<Cache<Address, CovenantClass>>,
+    holders_cache: Arc<Cache<Denom, Arc<BTreeMap<Address, CoinValue>>>>,
 }
 
 impl Backend {
     /// Creates a new Backend that wraps around a given Client.
-    pub fn new(client: Client, indexer: Option<Indexer>) -> Self {
+    pub fn new(
+        client: Client,
+        indexer: Option<Indexer>,
+        chainidx: Option<ChainIndex>,
+        caches: &CacheConfig,
+    ) -> Self {
         Self {
             client,
             indexer: indexer.map(Arc::new),
+            chainidx,
             supply_cache: Default::default(),
 
-            address_summary_cache: Arc::new(
+            address_ledger_cache: Arc::new(
+                Cache::builder()
+                    .max_capacity(caches.address_capacity)
+                    .time_to_live(caches.address_ttl())
+                    .build(),
+            ),
+            address_coins_cache: Arc::new(
                 Cache::builder()
-                    .max_capacity(10000)
-                    .time_to_live(Duration::from_secs(30))
+                    .max_capacity(caches.address_capacity)
+                    .time_to_live(caches.address_ttl())
+                    .build(),
+            ),
+            covenant_class_cache: Arc::new(
+                Cache::builder()
+                    .max_capacity(caches.address_capacity)
+                    .time_to_live(caches.address_ttl())
                     .build(),
             ),
 
-            leaderboard_cache: Arc::new(
+            holders_cache: Arc::new(
                 Cache::builder()
-                    .max_capacity(10000)
-                    .time_to_live(Duration::from_secs(1800))
+                    .max_capacity(caches.leaderboard_capacity)
+                    .time_to_live(caches.leaderboard_ttl())
                     .build(),
             ),
         }
     }
 
+    /// Drops everything cached in memory. Indexes and the durable block cache are kept.
+    pub fn flush_caches(&self) {
+        self.address_ledger_cache.invalidate_all();
+        self.address_coins_cache.invalidate_all();
+        self.covenant_class_cache.invalidate_all();
+        self.holders_cache.invalidate_all();
+        self.supply_cache.clear();
+    }
+
+    /// Describes what the backend currently holds.
+    pub async fn state(&self) -> anyhow::Result<BackendState> {
+        let chain_indexed = match self.chainidx.as_ref() {
+            Some(chainidx) => chainidx.indexed_height().await?,
+            None => None,
+        };
+        Ok(BackendState {
+            has_indexer: self.indexer.is_some(),
+            indexed_highest: self.indexed_highest(),
+            chain_indexed,
+            address_ledgers_cached: self.address_ledger_cache.entry_count(),
+            holder_lists_cached: self.holders_cache.entry_count(),
+            supply_trackers: self.supply_cache.len(),
+        })
+    }
+
     /// Obtains the latest indexed height.
     pub fn indexed_highest(&self) -> BlockHeight {
         self.indexer
@@ -131,6 +347,11 @@ impl Backend {
             .unwrap_or_default()
     }
 
+    /// Obtains the chain index, if we have one.
+    pub fn chain_index(&self) -> Option<&ChainIndex> {
+        self.chainidx.as_ref()
+    }
+
     /// Obtains the latest blockchain header.
     pub async fn get_latest_header(&self) -> anyhow::Result<Header> {
         Ok(self.client.latest_snapshot().await?.current_header())
@@ -138,45 +359,152 @@ impl Backend {
 
     /// Searches for the transaction matching a given hash.
     pub async fn search_transaction(&self, txhash: TxHash) -> anyhow::Result<Option<BlockHeight>> {
-        Ok(self
+        if let Some(height) = self
             .indexer
             .as_ref()
-            .and_then(|i| i.txhash_to_height(txhash)))
+            .and_then(|i| i.txhash_to_height(txhash))
+        {
+            return Ok(Some(height));
+        }
+        match self.chainidx.as_ref() {
+            Some(chainidx) => Ok(chainidx
+                .find_transactions(&txhash.to_string(), 1)
+                .await?
+                .pop()
+                .map(|(_, height)| height)),
+            None => Ok(None),
+        }
     }
 
     /// Searches for the block matching a given hash.
     pub async fn search_block(&self, blkhash: HashVal) -> anyhow::Result<Option<BlockHeight>> {
-        Ok(self
+        if let Some(height) = self
             .indexer
             .as_ref()
-            .and_then(|i| i.blkhash_to_height(blkhash)))
+            .and_then(|i| i.blkhash_to_height(blkhash))
+        {
+            return Ok(Some(height));
+        }
+        match self.chainidx.as_ref() {
+            Some(chainidx) => Ok(chainidx
+                .find_blocks(&blkhash.to_string(), 1)
+                .await?
+                .pop()
+                .map(|(_, height)| height)),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds transactions whose hash starts with a hex prefix. Only available if we have a chain index.
+    pub async fn find_transactions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(TxHash, BlockHeight)>> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        chainidx.find_transactions(prefix, limit).await
     }
 
-    /// Get "overview" information at either the latest height or a given height.
-    pub async fn get_overview(&self, height: Option<BlockHeight>) -> anyhow::Result<Overview> {
+    /// Finds blocks whose hash starts with a hex prefix. Only available if we have a chain index.
+    pub async fn find_blocks(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(HashVal, BlockHeight)>> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        chainidx.find_blocks(prefix, limit).await
+    }
+
+    /// Get "overview" information at either the latest height or a given height, including the given number of recent blocks, optionally with their transactions.
+    pub async fn get_overview(
+        &self,
+        height: Option<BlockHeight>,
+        block_count: usize,
+        include_transactions: bool,
+    ) -> anyhow::Result<Overview> {
         let last_snap = match height {
             Some(height) => self.client.snapshot(height).await?,
             None => self.client.latest_snapshot().await?,
         };
+        let tip_height = last_snap.current_header().height;
 
-        let mut futs = get_old_blocks(&last_snap, 50);
+        let mut futs = get_old_blocks(&last_snap, block_count);
 
         let mut blocks: Vec<BlockSummary> = vec![];
         while let Some(inner) = futs.next().await {
             let (block, reward) = inner?;
-            blocks.push(BlockSummary::from_block(block, reward))
+            let mut summary = BlockSummary::from_block(block, reward);
+            if !include_transactions {
+                summary.transactions.clear();
+            }
+            blocks.push(summary)
         }
 
         let erg_per_mel = get_exchange(&last_snap, Denom::Mel, Denom::Erg).await?;
         let sym_per_mel = get_exchange(&last_snap, Denom::Mel, Denom::Sym).await?;
 
+        let (day_stats, total_staked, block_interval_secs) = match self.indexed_chain().await {
+            Ok((chainidx, indexed)) => {
+                let end = indexed.min(tip_height);
+                let start = BlockHeight(end.0.saturating_sub(BLOCKS_PER_DAY - 1));
+                let day_stats = chainidx.get_block_range_stats(start, end).await?;
+                let total_staked = self
+                    .get_active_stakes(Some(end))
+                    .await?
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|stake| stake.syms_staked)
+                    .sum();
+                let block_interval_secs = chainidx.estimate_block_interval().await?;
+                (Some(day_stats), Some(total_staked), block_interval_secs)
+            }
+            Err(_) => (None, None, None),
+        };
+
         Ok(Overview {
             erg_per_mel,
             sym_per_mel,
             recent_blocks: blocks,
+            tip_height,
+            block_interval_secs,
+            day_transactions: day_stats.map(|s| s.transactions),
+            day_fees: day_stats.map(|s| s.fees),
+            total_staked,
         })
     }
 
+    /// Lists block summaries from one height towards another, both inclusive. Blocks are listed in descending order if `from` is above `to`. `from` defaults to the latest block and `to` to the genesis block.
+    pub async fn get_blocks(
+        &self,
+        from: Option<BlockHeight>,
+        to: Option<BlockHeight>,
+        limit: usize,
+    ) -> anyhow::Result<BlockPage> {
+        let last_snap = self.client.latest_snapshot().await?;
+        let tip = last_snap.current_header().height;
+        let from = from.unwrap_or(tip).min(tip).0;
+        let to = to.unwrap_or_default().min(tip).0;
+        let heights: Vec<u64> = if from >= to {
+            (to..=from).rev().take(limit).collect()
+        } else {
+            (from..=to).take(limit).collect()
+        };
+        let next = match heights.last() {
+            Some(&last) if last != to => {
+                Some(BlockHeight(if from >= to { last - 1 } else { last + 1 }))
+            }
+            _ => None,
+        };
+
+        let mut futs = get_blocks(&last_snap, heights);
+        let mut blocks = vec![];
+        while let Some(inner) = futs.next().await {
+            let (block, reward) = inner?;
+            blocks.push(BlockSummary::from_block(block, reward))
+        }
+        Ok(BlockPage { blocks, next })
+    }
+
     /// Obtains a specific transaction at a particular height.
     pub async fn get_transaction_at_height(
         &self,
@@ -246,9 +574,7 @@ impl Backend {
                     .or_insert_with(|| indexer.query_coins().denom(denom).balance_tracker().into())
                     .value()
                     .clone();
-                let b = tracker.balance_at(height.0);
-                eprintln!("got {} => {:?}", height, b);
-                Ok(b)
+                Ok(tracker.balance_at(height.0))
             } else {
                 Ok(None)
             }
@@ -258,51 +584,180 @@ impl Backend {
 
     /// Gets the total summary of some address. Only available if we have an indexer.
     pub async fn get_address_summary(&self, address: Address) -> anyhow::Result<AddressSummary> {
+        let class = self.get_covenant_class(address).await?;
+        let ledger = self.get_address_ledger(address).await?;
+        Ok(AddressSummary {
+            balances: ledger
+                .balances
+                .iter()
+                .map(|(denom, value)| (denom.to_string(), value.0 as f64 / 1_000_000.0))
+                .collect(),
+            class,
+            transactions: ledger
+                .transactions
+                .iter()
+                .map(|entry| AddressTransactionSummary {
+                    height: entry.height,
+                    date: height_to_datetime(entry.height),
+                    txhash: entry.txhash,
+                    deltas: entry
+                        .deltas
+                        .iter()
+                        .map(|(denom, delta)| (denom.to_string(), *delta as f64 / 1_000_000.0))
+                        .collect(),
+                })
+                .collect(),
+        })
+    }
+
+    /// Gets the exact balances and history of some address. Only available if we have an indexer.
+    pub async fn get_address_ledger(&self, address: Address) -> anyhow::Result<Arc<AddressLedger>> {
         let this = self.clone();
         smol::unblock(move || {
-            this.address_summary_cache
+            this.address_ledger_cache
                 .try_get_with(address, || {
                     let indexer = this.indexer.as_ref().context("no indexer")?.clone();
                     let current_coins = indexer.query_coins().covhash(address).unspent();
-                    let mut balances: BTreeMap<String, f64> = BTreeMap::new();
+                    let mut balances: BTreeMap<Denom, CoinValue> = BTreeMap::new();
                     for coin in current_coins.iter() {
-                        *balances
-                            .entry(coin.coin_data.denom.to_string())
-                            .or_default() += coin.coin_data.value.0 as f64 / 1_000_000.0;
+                        *balances.entry(coin.coin_data.denom).or_default() += coin.coin_data.value;
                     }
                     // TODO more efficient way of doing this
-                    let mut transactions: BTreeMap<(TxHash, BlockHeight), BTreeMap<String, f64>> =
+                    let mut transactions: BTreeMap<(TxHash, BlockHeight), BTreeMap<Denom, i128>> =
                         BTreeMap::new();
                     for coin in indexer.query_coins().covhash(address).iter() {
+                        let value = coin.coin_data.value.0 as i128;
                         let mapping = transactions
                             .entry((coin.create_txhash, coin.create_height))
                             .or_default();
                         // we credit the transaction that produced the coin
-                        *mapping.entry(coin.coin_data.denom.to_string()).or_default() +=
-                            coin.coin_data.value.0 as f64 / 1_000_000.0;
+                        *mapping.entry(coin.coin_data.denom).or_default() += value;
                         // and debit the transaction that spent the coin
                         if let Some(s) = coin.spend_info {
                             let mapping = transactions
                                 .entry((s.spend_txhash, s.spend_height))
                                 .or_default();
-                            // we credit the transaction that produced the coin
-                            *mapping.entry(coin.coin_data.denom.to_string()).or_default() -=
-                                coin.coin_data.value.0 as f64 / 1_000_000.0;
+                            *mapping.entry(coin.coin_data.denom).or_default() -= value;
                         }
                     }
-                    anyhow::Ok(AddressSummary {
+                    anyhow::Ok(Arc::new(AddressLedger {
                         balances,
                         transactions: transactions
                             .into_iter()
-                            .map(|(k, v)| AddressTransactionSummary {
+                            .map(|(k, v)| LedgerEntry {
                                 height: k.1,
-                                date: height_to_datetime(k.1),
-                                deltas: v,
                                 txhash: k.0,
+                                deltas: v,
                             })
                             .collect_vec()
                             .tap_mut(|v| v.sort_unstable_by_key(|v| v.height)),
-                    })
+                    }))
+                })
+                .map_err(|e| anyhow::anyhow!("{:?}", e))
+        })
+        .await
+    }
+
+    /// Gets the balances of some address as of the end of a given height, defaulting to the latest indexed height. Returns None if the height has not been indexed yet. Only available if we have an indexer.
+    pub async fn get_address_balances(
+        &self,
+        address: Address,
+        height: Option<BlockHeight>,
+    ) -> anyhow::Result<Option<AddressBalances>> {
+        let indexed = self.indexed_highest();
+        let height = height.unwrap_or(indexed);
+        if height > indexed {
+            return Ok(None);
+        }
+        let ledger = self.get_address_ledger(address).await?;
+        Ok(Some(AddressBalances {
+            height,
+            date: height_to_datetime(height),
+            balances: ledger
+                .balances_at(height)
+                .into_iter()
+                .map(|(denom, value)| (denom.to_string(), value))
+                .collect(),
+        }))
+    }
+
+    /// Lists the coins of some address, newest first, starting after the given coin. Only available if we have an indexer.
+    pub async fn get_address_coins(
+        &self,
+        address: Address,
+        status: CoinStatus,
+        denom: Option<Denom>,
+        after: Option<(BlockHeight, CoinID)>,
+        limit: usize,
+    ) -> anyhow::Result<CoinPage> {
+        let all_coins = self.get_all_address_coins(address).await?;
+        let sort_key = |height: BlockHeight, coinid: CoinID| {
+            (std::cmp::Reverse(height), coinid.txhash, coinid.index)
+        };
+        let start = match after {
+            Some((height, coinid)) => all_coins.partition_point(|coin| {
+                sort_key(coin.create_height, coin.coinid) <= sort_key(height, coinid)
+            }),
+            None => 0,
+        };
+        let mut coins = all_coins[start..]
+            .iter()
+            .filter(|coin| match status {
+                CoinStatus::All => true,
+                CoinStatus::Unspent => coin.spend_height.is_none(),
+                CoinStatus::Spent => coin.spend_height.is_some(),
+            })
+            .filter(|coin| denom.map(|denom| coin.denom == denom).unwrap_or(true))
+            .take(limit + 1)
+            .cloned()
+            .collect_vec();
+        let next = if coins.len() > limit {
+            coins.truncate(limit);
+            coins
+                .last()
+                .map(|coin| format!("{}:{}", coin.create_height, coin.coinid))
+        } else {
+            None
+        };
+        Ok(CoinPage { coins, next })
+    }
+
+    /// Gets every coin an address ever had, newest first, caching the list as long as address ledgers so that paging through it doesn't reload it.
+    async fn get_all_address_coins(
+        &self,
+        address: Address,
+    ) -> anyhow::Result<Arc<Vec<AddressCoin>>> {
+        let this = self.clone();
+        smol::unblock(move || {
+            this.address_coins_cache
+                .try_get_with(address, || {
+                    let indexer = this.indexer.as_ref().context("no indexer")?.clone();
+                    let mut coins = indexer
+                        .query_coins()
+                        .covhash(address)
+                        .iter()
+                        .map(|coin| AddressCoin {
+                            coinid: CoinID {
+                                txhash: coin.create_txhash,
+                                index: coin.create_index,
+                            },
+                            value: coin.coin_data.value,
+                            denom: coin.coin_data.denom,
+                            additional_data: coin.coin_data.additional_data_hex(),
+                            create_height: coin.create_height,
+                            create_txhash: coin.create_txhash,
+                            spend_height: coin.spend_info.as_ref().map(|s| s.spend_height),
+                            spend_txhash: coin.spend_info.as_ref().map(|s| s.spend_txhash),
+                        })
+                        .collect_vec();
+                    coins.sort_unstable_by_key(|coin| {
+                        (
+                            std::cmp::Reverse(coin.create_height),
+                            coin.coinid.txhash,
+                            coin.coinid.index,
+                        )
+                    });
+                    anyhow::Ok(Arc::new(coins))
                 })
                 .map_err(|e| anyhow::anyhow!("{:?}", e))
         })
@@ -311,21 +766,200 @@ impl Backend {
 
     /// Gets the leaderboard for a particular denomination.
     pub async fn get_leaderboard(&self, denom: Denom) -> anyhow::Result<BTreeMap<String, f64>> {
+        Ok(self
+            .get_holders(denom)
+            .await?
+            .iter()
+            .map(|(address, value)| (address.to_string(), value.0 as f64 / 1_000_000.0))
+            .collect())
+    }
+
+    /// Gets the exact unspent balance of every address holding a particular denomination. Only available if we have an indexer.
+    pub async fn get_holders(
+        &self,
+        denom: Denom,
+    ) -> anyhow::Result<Arc<BTreeMap<Address, CoinValue>>> {
         let this = self.clone();
         smol::unblock(move || {
-            this.leaderboard_cache.try_get_with(denom, || {
+            this.holders_cache.try_get_with(denom, || {
                 let indexer = this.indexer.as_ref().context("no indexer")?;
-                anyhow::Ok(indexer.query_coins().unspent().denom(denom).iter().fold(
-                    BTreeMap::new(),
-                    |mut map, cinfo| {
-                        *map.entry(cinfo.coin_data.covhash.to_string()).or_default() +=
-                            cinfo.coin_data.value.0 as f64 / 1_000_000.0;
-                        map
-                    },
+                anyhow::Ok(Arc::new(
+                    indexer.query_coins().unspent().denom(denom).iter().fold(
+                        BTreeMap::new(),
+                        |mut map, cinfo| {
+                            *map.entry(cinfo.coin_data.covhash).or_default() +=
+                                cinfo.coin_data.value;
+                            map
+                        },
+                    ),
                 ))
             })
         })
         .await
         .map_err(|e| anyhow::anyhow!("{:?}", e))
     }
+
+    /// Gets a covenant by its hash, if it was ever revealed on-chain. Only available if we have a chain index.
+    pub async fn get_covenant(&self, covhash: Address) -> anyhow::Result<Option<CovenantSummary>> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        let record = match chainidx.get_covenant(covhash, MAX_COVENANT_REVEALS).await? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        Ok(Some(CovenantSummary {
+            bytes: hex::encode(&record.bytes),
+            class: classify_address(covhash, Some(record.bytes.as_slice())),
+            disassembly: disassemble(&record.bytes)?,
+            first_reveal: record.first_reveal,
+            reveal_count: record.reveal_count,
+            reveals: record.reveals,
+        }))
+    }
+
+    /// Classifies the covenant locking an address, caching the class as long as address ledgers. The covenant is only known if we have a chain index and some transaction has revealed it.
+    pub async fn get_covenant_class(&self, address: Address) -> anyhow::Result<CovenantClass> {
+        if let Some(class) = self.covenant_class_cache.get(&address) {
+            return Ok(class);
+        }
+        let covenant = match self.chainidx.as_ref() {
+            Some(chainidx) => chainidx.get_covenant(address, 0).await?.map(|r| r.bytes),
+            None => None,
+        };
+        let class = classify_address(address, covenant.as_deref());
+        self.covenant_class_cache.insert(address, class.clone());
+        Ok(class)
+    }
+
+    /// Obtains the chain index along with the height it has completely indexed.
+    async fn indexed_chain(&self) -> anyhow::Result<(&ChainIndex, BlockHeight)> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        let height = chainidx
+            .indexed_height()
+            .await?
+            .context("chain index has not indexed any blocks yet")?;
+        Ok((chainidx, height))
+    }
+
+    /// Lists the stakes active at a given height, or at the latest indexed height. Returns None if the height has not been indexed yet. Only available if we have a chain index.
+    pub async fn get_active_stakes(
+        &self,
+        height: Option<BlockHeight>,
+    ) -> anyhow::Result<Option<Vec<StakeRecord>>> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        let height = height.unwrap_or(indexed);
+        if height > indexed {
+            return Ok(None);
+        }
+        let epoch = height.epoch();
+        Ok(Some(
+            chainidx
+                .get_stakes(height)
+                .await?
+                .into_iter()
+                .filter(|stake| stake.active_in(epoch))
+                .collect(),
+        ))
+    }
+
+    /// Sums up the SYM staked in every epoch up to the latest indexed one. Only available if we have a chain index.
+    pub async fn get_epoch_stakes(&self) -> anyhow::Result<Vec<EpochStake>> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        let stakes = chainidx.get_stakes(indexed).await?;
+        Ok((0..=indexed.epoch())
+            .map(|epoch| {
+                let active = stakes.iter().filter(|stake| stake.active_in(epoch));
+                EpochStake {
+                    epoch,
+                    total_staked: active.clone().map(|stake| stake.syms_staked).sum(),
+                    stakers: active.map(|stake| stake.pubkey).unique().count(),
+                }
+            })
+            .collect())
+    }
+
+    /// Gets the full staking history of a public key. Only available if we have a chain index.
+    pub async fn get_staker_history(&self, pubkey: Ed25519PK) -> anyhow::Result<StakerHistory> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        let stakes = chainidx.get_stakes_by_pubkey(pubkey).await?;
+        let epoch = indexed.epoch();
+        Ok(StakerHistory {
+            pubkey,
+            active_staked: stakes
+                .iter()
+                .filter(|stake| stake.height <= indexed && stake.active_in(epoch))
+                .map(|stake| stake.syms_staked)
+                .sum(),
+            stakes,
+        })
+    }
+
+    /// Ranks proposer reward recipients between two heights, which default to the whole indexed chain. Only available if we have a chain index.
+    pub async fn get_proposer_leaderboard(
+        &self,
+        start: Option<BlockHeight>,
+        end: Option<BlockHeight>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ProposerStats>> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        let end = end.unwrap_or(indexed).min(indexed);
+        let start = start.unwrap_or_default();
+        chainidx.get_proposer_stats(start, end, None, limit).await
+    }
+
+    /// Gets the proposer rewards received by a covenant, if it has received any. Only available if we have a chain index.
+    pub async fn get_proposer(&self, address: Address) -> anyhow::Result<Option<ProposerSummary>> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        let stats = match chainidx
+            .get_proposer_stats(BlockHeight(0), indexed, Some(address), 1)
+            .await?
+            .pop()
+        {
+            Some(stats) => stats,
+            None => return Ok(None),
+        };
+        let epochs = chainidx
+            .get_epoch_rewards(BlockHeight(0), indexed, Some(address))
+            .await?;
+        Ok(Some(ProposerSummary { stats, epochs }))
+    }
+
+    /// Sums up the proposer rewards paid out in each epoch between two heights. Only available if we have a chain index.
+    pub async fn get_epoch_rewards(
+        &self,
+        start: BlockHeight,
+        end: BlockHeight,
+    ) -> anyhow::Result<Vec<EpochRewards>> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        chainidx
+            .get_epoch_rewards(start, end.min(indexed), None)
+            .await
+    }
+
+    /// Summarizes an epoch, if it has started. Only available if we have a chain index.
+    pub async fn get_epoch(&self, epoch: u64) -> anyhow::Result<Option<EpochSummary>> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        chainidx.get_epoch_summary(epoch).await
+    }
+
+    /// Summarizes every epoch that has started, newest first. Only available if we have a chain index.
+    pub async fn get_epochs(&self) -> anyhow::Result<Vec<EpochSummary>> {
+        let chainidx = self.chainidx.as_ref().context("no chain index")?;
+        chainidx.get_epoch_summaries().await
+    }
+
+    /// Searches for transactions matching a filter, newest first. Only available if we have a chain index.
+    pub async fn search_transactions(&self, mut filter: TxFilter) -> anyhow::Result<TxSearchPage> {
+        let (chainidx, indexed) = self.indexed_chain().await?;
+        filter.end = filter.end.min(indexed);
+        let limit = filter.limit;
+        let transactions = chainidx.search_transactions(filter).await?;
+        let next = if transactions.len() == limit {
+            transactions
+                .last()
+                .map(|tx| format!("{}:{}", tx.height, tx.txhash))
+        } else {
+            None
+        };
+        Ok(TxSearchPage { transactions, next })
+    }
 }
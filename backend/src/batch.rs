@@ -0,0 +1,158 @@
+use futures_util::{stream, Future, StreamExt};
+use melstructs::{Address, BlockHeight, CoinID, TxHash};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{globals::BACKEND, upstream::upstream};
+
+/// At most how many lookups a single batch can contain.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// At most how large a batch request body can be, which is plenty for `MAX_BATCH_SIZE` lookups.
+pub const MAX_BATCH_BODY_BYTES: u64 = 128 * 1024;
+
+/// At most how many lookups of a batch run at the same time.
+const MAX_BATCH_CONCURRENCY: usize = 16;
+
+/// One lookup in a batch.
+#[serde_as]
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BatchQuery {
+    /// The same summary as `/raw/address/<address>`.
+    Address {
+        #[serde_as(as = "DisplayFromStr")]
+        address: Address,
+    },
+    /// A coin as of some height.
+    Coin {
+        height: BlockHeight,
+        #[serde_as(as = "DisplayFromStr")]
+        coinid: CoinID,
+    },
+    /// A transaction, which is searched for if no height is given.
+    Transaction {
+        #[serde_as(as = "DisplayFromStr")]
+        txhash: TxHash,
+        #[serde(default)]
+        height: Option<BlockHeight>,
+    },
+    /// A block summary.
+    Block { height: BlockHeight },
+}
+
+/// The outcome of one lookup. Lookups that found nothing have a null result.
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum BatchResult {
+    Ok { result: serde_json::Value },
+    Err { error: String },
+}
+
+/// Runs a batch of lookups concurrently, returning their results in the same order.
+pub async fn run_batch(queries: Vec<BatchQuery>) -> Vec<BatchResult> {
+    run_all(queries, run_query).await
+}
+
+async fn run_all<F: Future<Output = anyhow::Result<serde_json::Value>>>(
+    queries: Vec<BatchQuery>,
+    run: impl Fn(BatchQuery) -> F,
+) -> Vec<BatchResult> {
+    stream::iter(queries)
+        .map(|query| {
+            let result = run(query);
+            async move {
+                match result.await {
+                    Ok(result) => BatchResult::Ok { result },
+                    Err(err) => BatchResult::Err {
+                        error: err.to_string(),
+                    },
+                }
+            }
+        })
+        .buffered(MAX_BATCH_CONCURRENCY)
+        .collect()
+        .await
+}
+
+async fn run_query(query: BatchQuery) -> anyhow::Result<serde_json::Value> {
+    let value = match query {
+        BatchQuery::Address { address } => {
+            serde_json::to_value(upstream(BACKEND.get_address_summary(address)).await?)?
+        }
+        BatchQuery::Coin { height, coinid } => {
+            serde_json::to_value(upstream(BACKEND.get_coin_at_height(height, coinid)).await?)?
+        }
+        BatchQuery::Transaction { txhash, height } => {
+            let height = match height {
+                Some(height) => Some(height),
+                None => BACKEND.search_transaction(txhash).await?,
+            };
+            match height {
+                Some(height) => serde_json::to_value(
+                    upstream(BACKEND.get_transaction_at_height(height, txhash)).await?,
+                )?,
+                None => serde_json::Value::Null,
+            }
+        }
+        BatchQuery::Block { height } => {
+            serde_json::to_value(upstream(BACKEND.get_block_summary(height)).await?)?
+        }
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn block(height: u64) -> BatchQuery {
+        BatchQuery::Block {
+            height: BlockHeight(height),
+        }
+    }
+
+    #[test]
+    fn results_keep_query_order() {
+        let queries = (0..40).map(block).collect();
+        let results = smol::block_on(run_all(queries, |query| async move {
+            let height = match query {
+                BatchQuery::Block { height } => height.0,
+                _ => unreachable!(),
+            };
+            // later queries finish first
+            smol::Timer::after(Duration::from_millis(40 - height)).await;
+            if height % 10 == 3 {
+                anyhow::bail!("no block {}", height)
+            }
+            Ok(serde_json::json!(height))
+        }));
+        assert_eq!(results.len(), 40);
+        for (height, result) in results.into_iter().enumerate() {
+            match result {
+                BatchResult::Ok { result } => assert_eq!(result, serde_json::json!(height)),
+                BatchResult::Err { error } => {
+                    assert_eq!(height % 10, 3);
+                    assert_eq!(error, format!("no block {}", height));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_queries() {
+        let queries: Vec<BatchQuery> = serde_json::from_value(serde_json::json!([
+            {"kind": "block", "height": 5},
+            {"kind": "transaction", "txhash": "0000000000000000000000000000000000000000000000000000000000000000"},
+        ]))
+        .unwrap();
+        assert!(matches!(queries[0], BatchQuery::Block { height } if height == BlockHeight(5)));
+        assert!(matches!(
+            queries[1],
+            BatchQuery::Transaction { height: None, .. }
+        ));
+        assert!(serde_json::from_value::<BatchQuery>(serde_json::json!({"kind": "pool"})).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use melstructs::{Block, BlockHeight, CoinValue};
+use rusqlite::{params, OptionalExtension};
+
+use crate::localdb::LocalDb;
+
+/// A durable cache of blocks and their proposer rewards, kept in the local database. Confirmed blocks are final, so entries never need to be invalidated.
+#[derive(Clone)]
+pub struct BlockCache {
+    db: LocalDb,
+}
+
+impl BlockCache {
+    /// Creates a block cache backed by the given database, creating the table if needed.
+    pub fn new(db: LocalDb) -> anyhow::Result<Self> {
+        db.run_blocking(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS block_cache (
+                    height INTEGER PRIMARY KEY,
+                    data BLOB NOT NULL
+                );",
+            )?;
+            Ok(())
+        })?;
+        Ok(Self { db })
+    }
+
+    /// Looks up a cached block and its proposer reward.
+    pub async fn get(&self, height: BlockHeight) -> anyhow::Result<Option<(Block, CoinValue)>> {
+        let data = self
+            .db
+            .run(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT data FROM block_cache WHERE height = ?1",
+                        params![height.0 as i64],
+                        |row| row.get::<_, Vec<u8>>(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        match data {
+            Some(data) => Ok(Some(stdcode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Caches a block and its proposer reward.
+    pub async fn insert(&self, block: &Block, reward: CoinValue) -> anyhow::Result<()> {
+        let height = block.header.height;
+        let data = stdcode::serialize(&(block, reward))?;
+        self.db
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO block_cache (height, data) VALUES (?1, ?2)",
+                    params![height.0 as i64, data],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
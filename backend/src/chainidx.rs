@@ -0,0 +1,1329 @@
+use std::{collections::BTreeMap, ops::RangeInclusive, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use futures_util::{Stream, StreamExt};
+use melprot::{Client, Snapshot};
+use melstf::GenesisConfig;
+use melstructs::{
+    Address, Block, BlockHeight, CoinDataHeight, CoinID, CoinValue, Denom, NetID, StakeDoc,
+    Transaction, TxHash, TxKind, STAKE_EPOCH,
+};
+use rusqlite::{params, types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+use crate::{localdb::LocalDb, upstream::upstream};
+
+/// Changes to the chain index's schema, oldest first. Each runs once, and the number that have run is stored as the schema version, so new ones must only ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS covenants (
+            covhash TEXT PRIMARY KEY,
+            bytes BLOB NOT NULL,
+            first_height INTEGER NOT NULL,
+            first_txhash TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS covenant_reveals (
+            covhash TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            txhash TEXT NOT NULL,
+            PRIMARY KEY (covhash, txhash)
+        );
+        CREATE INDEX IF NOT EXISTS covenant_reveals_height ON covenant_reveals (covhash, height);",
+        backfill: &[Part::Covenants],
+    },
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS stakes (
+            txhash TEXT PRIMARY KEY,
+            height INTEGER NOT NULL,
+            pubkey TEXT NOT NULL,
+            e_start INTEGER NOT NULL,
+            e_post_end INTEGER NOT NULL,
+            syms_staked TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS stakes_pubkey ON stakes (pubkey, height);",
+        backfill: &[Part::Stakes],
+    },
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS proposer_rewards (
+            height INTEGER PRIMARY KEY,
+            covhash TEXT NOT NULL,
+            reward INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS proposer_rewards_covhash ON proposer_rewards (covhash, height);",
+        backfill: &[Part::ProposerRewards],
+    },
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS block_stats (
+            height INTEGER PRIMARY KEY,
+            tx_count INTEGER NOT NULL,
+            fees INTEGER NOT NULL,
+            dosc_minted INTEGER NOT NULL,
+            fee_multiplier INTEGER NOT NULL
+        );
+        -- when blocks reached the index while it was following the tip, since headers carry no timestamps
+        CREATE TABLE IF NOT EXISTS block_arrivals (
+            height INTEGER PRIMARY KEY,
+            seen INTEGER NOT NULL
+        );
+        -- only epochs that are over are stored, since the summary of a running one still changes
+        CREATE TABLE IF NOT EXISTS epoch_summaries (
+            epoch INTEGER PRIMARY KEY,
+            summary TEXT NOT NULL CHECK (json_extract(summary, '$.finalized') = 1)
+        );",
+        backfill: &[Part::BlockStats],
+    },
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS block_hashes (
+            blkhash TEXT PRIMARY KEY,
+            height INTEGER NOT NULL
+        );",
+        backfill: &[Part::BlockHashes],
+    },
+    Migration {
+        sql: "CREATE TABLE IF NOT EXISTS transactions (
+            txhash TEXT PRIMARY KEY,
+            height INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            fee INTEGER NOT NULL,
+            covenant_count INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS transactions_height ON transactions (height);
+        CREATE INDEX IF NOT EXISTS transactions_kind ON transactions (kind, height);
+        CREATE TABLE IF NOT EXISTS tx_denoms (
+            txhash TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            denom TEXT NOT NULL,
+            value INTEGER NOT NULL,
+            PRIMARY KEY (txhash, denom)
+        );
+        CREATE INDEX IF NOT EXISTS tx_denoms_denom ON tx_denoms (denom, height);
+        CREATE TABLE IF NOT EXISTS tx_addresses (
+            txhash TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            covhash TEXT NOT NULL,
+            PRIMARY KEY (txhash, covhash)
+        );
+        CREATE INDEX IF NOT EXISTS tx_addresses_covhash ON tx_addresses (covhash, height);",
+        backfill: &[Part::Transactions],
+    },
+];
+
+/// How many blocks a backfill goes through before the indexer checks for new blocks again.
+const BACKFILL_CHUNK: u64 = 10_000;
+
+/// How many blocks are fetched from upstream concurrently while catching up.
+const FETCH_CONCURRENCY: usize = 16;
+
+/// How many blocks are written to the database in a single SQLite transaction.
+const BATCH_SIZE: usize = 200;
+
+/// How long to wait between checking for new blocks once caught up.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Blocks indexed while at most this many blocks behind the tip have their arrival time recorded. Blocks indexed while catching up arrive in bursts, so their arrival times say nothing about when they were made.
+const LIVE_LAG: u64 = 2;
+
+/// How many of the most recent arrivals the block interval is estimated from.
+const INTERVAL_SAMPLE: usize = 120;
+
+/// The fewest blocks the block interval is estimated over. Arrival times are only as precise as the poll interval, so fewer would give a noisy estimate.
+const MIN_INTERVAL_SPAN: u64 = 20;
+
+/// A transaction that revealed a covenant.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reveal {
+    pub height: BlockHeight,
+    pub txhash: TxHash,
+}
+
+/// What the chain index knows about a covenant.
+#[derive(Clone, Debug)]
+pub struct CovenantRecord {
+    pub bytes: Vec<u8>,
+    pub first_reveal: Reveal,
+    pub reveal_count: u64,
+    /// The most recent reveals, newest first.
+    pub reveals: Vec<Reveal>,
+}
+
+/// A stake document, along with the transaction that created it.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct StakeRecord {
+    pub txhash: TxHash,
+    pub height: BlockHeight,
+    pub pubkey: Ed25519PK,
+    /// The first epoch in which the stake is effective.
+    pub e_start: u64,
+    /// The epoch after the last one in which the stake is effective.
+    pub e_post_end: u64,
+    pub syms_staked: CoinValue,
+}
+
+impl StakeRecord {
+    /// Whether the stake is effective during the given epoch.
+    pub fn active_in(&self, epoch: u64) -> bool {
+        self.e_start <= epoch && epoch < self.e_post_end
+    }
+}
+
+/// Proposer rewards received by one covenant, over some range of blocks.
+#[serde_as]
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct ProposerStats {
+    #[serde_as(as = "DisplayFromStr")]
+    pub address: Address,
+    /// Number of blocks whose proposer reward went to this covenant.
+    pub blocks: u64,
+    pub total_rewards: CoinValue,
+    pub first_height: BlockHeight,
+    pub last_height: BlockHeight,
+}
+
+/// Proposer rewards paid out during one epoch.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct EpochRewards {
+    pub epoch: u64,
+    pub blocks: u64,
+    pub total_rewards: CoinValue,
+}
+
+/// Aggregate statistics about one epoch.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EpochSummary {
+    pub epoch: u64,
+    pub start_height: BlockHeight,
+    /// The last block of the epoch, or the last indexed block if the epoch is not over yet.
+    pub end_height: BlockHeight,
+    /// Whether the epoch is over and fully indexed. Only finalized summaries are stored.
+    pub finalized: bool,
+    pub transactions: u64,
+    pub fees: CoinValue,
+    pub proposer_rewards: CoinValue,
+    /// ERG created by DOSC-minting transactions.
+    pub dosc_minted: CoinValue,
+    /// Number of stake transactions confirmed during the epoch.
+    pub new_stakes: u64,
+    /// SYM locked up by those stake transactions.
+    pub syms_newly_staked: CoinValue,
+    /// SYM effectively staked during the epoch.
+    pub syms_staked: CoinValue,
+    pub start_fee_multiplier: u128,
+    pub end_fee_multiplier: u128,
+}
+
+/// Filters for searching transactions. Every filter that is set has to match.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TxFilter {
+    pub start: BlockHeight,
+    pub end: BlockHeight,
+    pub kind: Option<TxKind>,
+    /// Only transactions with outputs of this denomination. Value bounds apply to the total output value in it.
+    pub denom: Option<Denom>,
+    pub min_value: Option<u128>,
+    pub max_value: Option<u128>,
+    /// Only transactions that send to this address, or spend from it.
+    pub address: Option<Address>,
+    pub has_covenant: Option<bool>,
+    /// Only transactions after this one, in the order results are listed.
+    pub after: Option<(BlockHeight, TxHash)>,
+    pub limit: usize,
+}
+
+/// A transaction that matched a search.
+#[serde_as]
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct TxRecord {
+    pub txhash: TxHash,
+    pub height: BlockHeight,
+    #[serde_as(as = "DisplayFromStr")]
+    pub kind: TxKind,
+    pub fee: CoinValue,
+    /// Total output value in the filtered denomination, if filtering by one.
+    pub value: Option<CoinValue>,
+}
+
+/// Activity over a range of blocks.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct BlockRangeStats {
+    pub transactions: u64,
+    pub fees: CoinValue,
+}
+
+/// A change to the chain index's schema.
+struct Migration {
+    sql: &'static str,
+    /// What the migration adds, which then has to be filled in for the blocks that were already indexed.
+    backfill: &'static [Part],
+}
+
+/// The groups of tables that are filled in block by block. A group added to an existing database is backfilled on its own, without re-indexing the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Part {
+    Covenants,
+    Stakes,
+    ProposerRewards,
+    BlockStats,
+    BlockHashes,
+    Transactions,
+}
+
+impl Part {
+    const ALL: &'static [Part] = &[
+        Part::Covenants,
+        Part::Stakes,
+        Part::ProposerRewards,
+        Part::BlockStats,
+        Part::BlockHashes,
+        Part::Transactions,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Part::Covenants => "covenants",
+            Part::Stakes => "stakes",
+            Part::ProposerRewards => "proposer_rewards",
+            Part::BlockStats => "block_stats",
+            Part::BlockHashes => "block_hashes",
+            Part::Transactions => "transactions",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Part::ALL.iter().copied().find(|part| part.as_str() == s)
+    }
+}
+
+/// A block, along with whatever else the chain index needs to know about it.
+struct BlockData {
+    block: Block,
+    /// The coin paying out the proposer reward, created by this block.
+    proposer_reward: Option<CoinDataHeight>,
+    /// How much ERG the block's DoscMint transactions spent, which is not newly minted.
+    dosc_erg_spent: u128,
+}
+
+/// Melscan's own secondary index over the chain, kept in the local database. Unlike the block indexer, which only tracks coins, this tracks things like which transactions revealed which covenants. A background task walks every block in order, so everything up to `indexed_height` is complete.
+#[derive(Clone)]
+pub struct ChainIndex {
+    db: LocalDb,
+    client: Client,
+}
+
+impl ChainIndex {
+    /// Creates a chain index backed by the given database, creating or migrating the tables if needed.
+    pub fn new(db: LocalDb, client: Client) -> anyhow::Result<Self> {
+        let network = client.netid();
+        db.run_blocking(|conn| {
+            migrate(conn)?;
+            index_genesis_stakes(conn, network)
+        })?;
+        Ok(Self { db, client })
+    }
+
+    /// The highest height that has been completely indexed.
+    pub async fn indexed_height(&self) -> anyhow::Result<Option<BlockHeight>> {
+        self.db
+            .run(|conn| {
+                let height = conn
+                    .query_row(
+                        "SELECT height FROM chainidx_progress WHERE id = 0",
+                        [],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .optional()?;
+                Ok(height.map(|h| BlockHeight(h as u64)))
+            })
+            .await
+    }
+
+    /// Runs the indexer forever, following the chain tip and backfilling whatever migrations added in between.
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.sync().await {
+                log::warn!("chain index failed to sync: {:?}", err);
+            }
+            match self.backfill().await {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(err) => log::warn!("chain index failed to backfill: {:?}", err),
+            }
+            smol::Timer::after(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn sync(&self) -> anyhow::Result<()> {
+        let snap = upstream(self.client.latest_snapshot()).await?;
+        let tip = snap.current_header().height;
+        let start = self.indexed_height().await?.map(|h| h.0 + 1).unwrap_or(1);
+        let live = tip.0.saturating_sub(start) < LIVE_LAG;
+        let mut blocks = fetch_blocks(snap, start..=tip.0);
+        let mut batch = vec![];
+        while let Some(block) = blocks.next().await {
+            batch.push(block?);
+            if batch.len() >= BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch), live).await?;
+            }
+        }
+        if !batch.is_empty() {
+            self.write_batch(batch, live).await?;
+        }
+        Ok(())
+    }
+
+    /// Backfills one chunk of the oldest pending backfill. Returns whether there was anything to backfill.
+    async fn backfill(&self) -> anyhow::Result<bool> {
+        let pending = self
+            .db
+            .run(|conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT part, height, until FROM chainidx_backfills ORDER BY rowid LIMIT 1",
+                        [],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, i64>(1)?,
+                                row.get::<_, i64>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let (name, height, until) = match pending {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+        let part = Part::parse(&name).with_context(|| format!("unknown backfill {:?}", name))?;
+        let (start, until) = (height as u64 + 1, until as u64);
+        let end = until.min(height as u64 + BACKFILL_CHUNK);
+        log::info!(
+            "chain index backfilling {} from height {} to {} of {}",
+            name,
+            start,
+            end,
+            until
+        );
+        let snap = upstream(self.client.latest_snapshot()).await?;
+        let mut blocks = fetch_blocks(snap, start..=end);
+        let mut batch = vec![];
+        while let Some(block) = blocks.next().await {
+            batch.push(block?);
+            if batch.len() >= BATCH_SIZE {
+                self.write_backfill(part, until, std::mem::take(&mut batch))
+                    .await?;
+            }
+        }
+        if !batch.is_empty() || start > end {
+            self.write_backfill(part, until, batch).await?;
+        }
+        Ok(true)
+    }
+
+    /// Indexes one part of a batch of consecutive blocks atomically, along with the backfill's progress.
+    async fn write_backfill(
+        &self,
+        part: Part,
+        until: u64,
+        blocks: Vec<BlockData>,
+    ) -> anyhow::Result<()> {
+        self.db
+            .run(move |conn| {
+                let dbtx = conn.transaction()?;
+                for data in blocks.iter() {
+                    index_block(&dbtx, data, &[part])?;
+                }
+                let last = blocks.last().map(|data| data.block.header.height.0);
+                if last.map(|last| last < until).unwrap_or(false) {
+                    dbtx.execute(
+                        "UPDATE chainidx_backfills SET height = ?2 WHERE part = ?1",
+                        params![part.as_str(), last.unwrap_or_default() as i64],
+                    )?;
+                } else {
+                    log::info!("chain index finished backfilling {}", part.as_str());
+                    dbtx.execute(
+                        "DELETE FROM chainidx_backfills WHERE part = ?1",
+                        params![part.as_str()],
+                    )?;
+                }
+                dbtx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Indexes a batch of consecutive blocks atomically.
+    async fn write_batch(&self, blocks: Vec<BlockData>, live: bool) -> anyhow::Result<()> {
+        let last = match blocks.last() {
+            Some(data) => data.block.header.height,
+            None => return Ok(()),
+        };
+        let now = chrono::Utc::now().timestamp();
+        self.db
+            .run(move |conn| {
+                let dbtx = conn.transaction()?;
+                for data in blocks.iter() {
+                    index_block(&dbtx, data, Part::ALL)?;
+                    if live {
+                        dbtx.execute(
+                            "INSERT OR IGNORE INTO block_arrivals (height, seen) VALUES (?1, ?2)",
+                            params![data.block.header.height.0 as i64, now],
+                        )?;
+                    }
+                }
+                dbtx.execute(
+                    "INSERT OR REPLACE INTO chainidx_progress (id, height) VALUES (0, ?1)",
+                    params![last.0 as i64],
+                )?;
+                dbtx.commit()?;
+                Ok(())
+            })
+            .await?;
+        log::debug!("chain index reached height {}", last);
+        Ok(())
+    }
+
+    /// Looks up a covenant by its hash, along with at most `max_reveals` of the transactions that revealed it.
+    pub async fn get_covenant(
+        &self,
+        covhash: Address,
+        max_reveals: usize,
+    ) -> anyhow::Result<Option<CovenantRecord>> {
+        self.db
+            .run(move |conn| {
+                let key = covhash.0.to_string();
+                let row = conn
+                    .query_row(
+                        "SELECT bytes, first_height, first_txhash FROM covenants WHERE covhash = ?1",
+                        params![key],
+                        |row| {
+                            Ok((
+                                row.get::<_, Vec<u8>>(0)?,
+                                row.get::<_, i64>(1)?,
+                                row.get::<_, String>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?;
+                let (bytes, first_height, first_txhash) = match row {
+                    Some(row) => row,
+                    None => return Ok(None),
+                };
+                let reveal_count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM covenant_reveals WHERE covhash = ?1",
+                    params![key],
+                    |row| row.get(0),
+                )?;
+                let mut stmt = conn.prepare(
+                    "SELECT height, txhash FROM covenant_reveals WHERE covhash = ?1 ORDER BY height DESC LIMIT ?2",
+                )?;
+                let rows = stmt.query_map(params![key, max_reveals as i64], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?;
+                let mut reveals = vec![];
+                for row in rows {
+                    let (height, txhash) = row?;
+                    reveals.push(parse_reveal(height, &txhash)?);
+                }
+                Ok(Some(CovenantRecord {
+                    bytes,
+                    first_reveal: parse_reveal(first_height, &first_txhash)?,
+                    reveal_count: reveal_count as u64,
+                    reveals,
+                }))
+            })
+            .await
+    }
+
+    /// Lists every stake created by a transaction up to the given height, oldest first.
+    pub async fn get_stakes(&self, max_height: BlockHeight) -> anyhow::Result<Vec<StakeRecord>> {
+        self.query_stakes(
+            "SELECT txhash, height, pubkey, e_start, e_post_end, syms_staked FROM stakes WHERE height <= ?1 ORDER BY height",
+            Value::Integer(max_height.0 as i64),
+        )
+        .await
+    }
+
+    /// Lists every stake made by a given staker, newest first.
+    pub async fn get_stakes_by_pubkey(
+        &self,
+        pubkey: Ed25519PK,
+    ) -> anyhow::Result<Vec<StakeRecord>> {
+        self.query_stakes(
+            "SELECT txhash, height, pubkey, e_start, e_post_end, syms_staked FROM stakes WHERE pubkey = ?1 ORDER BY height DESC",
+            Value::Text(pubkey.to_string()),
+        )
+        .await
+    }
+
+    async fn query_stakes(
+        &self,
+        sql: &'static str,
+        param: Value,
+    ) -> anyhow::Result<Vec<StakeRecord>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(sql)?;
+                let rows = stmt.query_map(params![param], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, String>(5)?,
+                    ))
+                })?;
+                let mut stakes = vec![];
+                for row in rows {
+                    let (txhash, height, pubkey, e_start, e_post_end, syms_staked) = row?;
+                    stakes.push(StakeRecord {
+                        txhash: TxHash(HashVal::from_str(&txhash)?),
+                        height: BlockHeight(height as u64),
+                        pubkey: Ed25519PK::from_str(&pubkey)?,
+                        e_start: e_start as u64,
+                        e_post_end: e_post_end as u64,
+                        syms_staked: CoinValue(syms_staked.parse()?),
+                    });
+                }
+                Ok(stakes)
+            })
+            .await
+    }
+
+    /// Ranks the covenants that received proposer rewards between two heights (inclusive) by how many blocks they were rewarded for. If an address is given, only that covenant is considered.
+    pub async fn get_proposer_stats(
+        &self,
+        start: BlockHeight,
+        end: BlockHeight,
+        address: Option<Address>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ProposerStats>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT covhash, COUNT(*) AS blocks, SUM(reward), MIN(height), MAX(height) FROM proposer_rewards
+                    WHERE height BETWEEN ?1 AND ?2 AND (?3 IS NULL OR covhash = ?3)
+                    GROUP BY covhash ORDER BY blocks DESC, covhash LIMIT ?4",
+                )?;
+                let rows = stmt.query_map(
+                    params![
+                        start.0 as i64,
+                        end.0 as i64,
+                        address.map(|a| a.0.to_string()),
+                        limit as i64
+                    ],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, i64>(2)?,
+                            row.get::<_, i64>(3)?,
+                            row.get::<_, i64>(4)?,
+                        ))
+                    },
+                )?;
+                let mut stats = vec![];
+                for row in rows {
+                    let (covhash, blocks, total_rewards, first_height, last_height) = row?;
+                    stats.push(ProposerStats {
+                        address: Address(HashVal::from_str(&covhash)?),
+                        blocks: blocks as u64,
+                        total_rewards: CoinValue(total_rewards as u128),
+                        first_height: BlockHeight(first_height as u64),
+                        last_height: BlockHeight(last_height as u64),
+                    });
+                }
+                Ok(stats)
+            })
+            .await
+    }
+
+    /// Sums up the proposer rewards paid out in each epoch between two heights (inclusive), optionally only those paid to one covenant.
+    pub async fn get_epoch_rewards(
+        &self,
+        start: BlockHeight,
+        end: BlockHeight,
+        address: Option<Address>,
+    ) -> anyhow::Result<Vec<EpochRewards>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT height / ?4 AS epoch, COUNT(*), SUM(reward) FROM proposer_rewards
+                    WHERE height BETWEEN ?1 AND ?2 AND (?3 IS NULL OR covhash = ?3)
+                    GROUP BY epoch ORDER BY epoch",
+                )?;
+                let rows = stmt.query_map(
+                    params![
+                        start.0 as i64,
+                        end.0 as i64,
+                        address.map(|a| a.0.to_string()),
+                        STAKE_EPOCH as i64
+                    ],
+                    |row| {
+                        Ok(EpochRewards {
+                            epoch: row.get::<_, i64>(0)? as u64,
+                            blocks: row.get::<_, i64>(1)? as u64,
+                            total_rewards: CoinValue(row.get::<_, i64>(2)? as u128),
+                        })
+                    },
+                )?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await
+    }
+
+    /// Finds transactions whose hash starts with the given hex prefix, returning at most `limit` of them.
+    pub async fn find_transactions(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(TxHash, BlockHeight)>> {
+        Ok(self
+            .find_by_prefix("transactions", "txhash", prefix, limit)
+            .await?
+            .into_iter()
+            .map(|(hash, height)| (TxHash(hash), height))
+            .collect())
+    }
+
+    /// Finds blocks whose hash starts with the given hex prefix, returning at most `limit` of them.
+    pub async fn find_blocks(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(HashVal, BlockHeight)>> {
+        self.find_by_prefix("block_hashes", "blkhash", prefix, limit)
+            .await
+    }
+
+    async fn find_by_prefix(
+        &self,
+        table: &'static str,
+        column: &'static str,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(HashVal, BlockHeight)>> {
+        let prefix = prefix.to_lowercase();
+        if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(vec![]);
+        }
+        self.db
+            .run(move |conn| {
+                // every hex digit sorts before 'g', so this range is exactly the hashes starting with the prefix, and can use the primary key
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {column}, height FROM {table} WHERE {column} >= ?1 AND {column} < ?2 ORDER BY height DESC LIMIT ?3"
+                ))?;
+                let rows = stmt.query_map(
+                    params![prefix, format!("{}g", prefix), limit as i64],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+                )?;
+                let mut found = vec![];
+                for row in rows {
+                    let (hash, height) = row?;
+                    found.push((HashVal::from_str(&hash)?, BlockHeight(height as u64)));
+                }
+                Ok(found)
+            })
+            .await
+    }
+
+    /// Searches for transactions, newest first. Ties within a block are broken by hash.
+    pub async fn search_transactions(&self, filter: TxFilter) -> anyhow::Result<Vec<TxRecord>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT t.txhash, t.height, t.kind, t.fee, d.value FROM transactions t
+                    LEFT JOIN tx_denoms d ON d.txhash = t.txhash AND d.denom = ?4
+                    WHERE t.height BETWEEN ?1 AND ?2
+                    AND (?3 IS NULL OR t.kind = ?3)
+                    AND (?4 IS NULL OR d.value BETWEEN ?5 AND ?6)
+                    AND (?7 IS NULL OR EXISTS (SELECT 1 FROM tx_addresses a WHERE a.txhash = t.txhash AND a.covhash = ?7))
+                    AND (?8 IS NULL OR (t.covenant_count > 0) = ?8)
+                    AND (?9 IS NULL OR t.height < ?9 OR (t.height = ?9 AND t.txhash > ?10))
+                    ORDER BY t.height DESC, t.txhash LIMIT ?11",
+                )?;
+                let rows = stmt.query_map(
+                    params![
+                        filter.start.0 as i64,
+                        filter.end.0 as i64,
+                        filter.kind.map(|k| k.to_string()),
+                        filter.denom.map(|d| d.to_string()),
+                        sql_int(filter.min_value.unwrap_or(0)),
+                        sql_int(filter.max_value.unwrap_or(u128::MAX)),
+                        filter.address.map(|a| a.0.to_string()),
+                        filter.has_covenant,
+                        filter.after.map(|(height, _)| height.0 as i64),
+                        filter.after.map(|(_, txhash)| txhash.to_string()),
+                        filter.limit as i64
+                    ],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, i64>(3)?,
+                            row.get::<_, Option<i64>>(4)?,
+                        ))
+                    },
+                )?;
+                let mut found = vec![];
+                for row in rows {
+                    let (txhash, height, kind, fee, value) = row?;
+                    found.push(TxRecord {
+                        txhash: TxHash(HashVal::from_str(&txhash)?),
+                        height: BlockHeight(height as u64),
+                        kind: TxKind::from_str(&kind)?,
+                        fee: CoinValue(fee as u128),
+                        value: value.map(|v| CoinValue(v as u128)),
+                    });
+                }
+                Ok(found)
+            })
+            .await
+    }
+
+    /// Counts the transactions and fees between two heights (inclusive).
+    pub async fn get_block_range_stats(
+        &self,
+        start: BlockHeight,
+        end: BlockHeight,
+    ) -> anyhow::Result<BlockRangeStats> {
+        self.db
+            .run(move |conn| {
+                let (transactions, fees) = conn.query_row(
+                    "SELECT COALESCE(SUM(tx_count), 0), COALESCE(SUM(fees), 0) FROM block_stats WHERE height BETWEEN ?1 AND ?2",
+                    params![start.0 as i64, end.0 as i64],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )?;
+                Ok(BlockRangeStats {
+                    transactions: transactions as u64,
+                    fees: CoinValue(fees as u128),
+                })
+            })
+            .await
+    }
+
+    /// Estimates the average number of seconds between blocks, from when recent blocks reached the index. Returns None until the index has followed the tip for long enough.
+    pub async fn estimate_block_interval(&self) -> anyhow::Result<Option<f64>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT height, seen FROM block_arrivals ORDER BY height DESC LIMIT ?1",
+                )?;
+                let arrivals = stmt
+                    .query_map(params![INTERVAL_SAMPLE as i64], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(mean_interval(&arrivals))
+            })
+            .await
+    }
+
+    /// Summarizes an epoch, if it has started. Summaries of finished epochs are computed once and then stored.
+    pub async fn get_epoch_summary(&self, epoch: u64) -> anyhow::Result<Option<EpochSummary>> {
+        let indexed = match self.indexed_height().await? {
+            Some(indexed) => indexed,
+            None => return Ok(None),
+        };
+        self.db
+            .run(move |conn| epoch_summary(conn, epoch, indexed))
+            .await
+    }
+
+    /// Summarizes every epoch that has started, newest first.
+    pub async fn get_epoch_summaries(&self) -> anyhow::Result<Vec<EpochSummary>> {
+        let indexed = match self.indexed_height().await? {
+            Some(indexed) => indexed,
+            None => return Ok(vec![]),
+        };
+        self.db
+            .run(move |conn| {
+                let mut summaries = vec![];
+                for epoch in (0..=indexed.epoch()).rev() {
+                    summaries.extend(epoch_summary(conn, epoch, indexed)?);
+                }
+                Ok(summaries)
+            })
+            .await
+    }
+}
+
+/// Fetches a range of blocks, along with what the chain index needs to know about them, in order.
+fn fetch_blocks(
+    snap: Snapshot,
+    heights: RangeInclusive<u64>,
+) -> impl Stream<Item = anyhow::Result<BlockData>> {
+    futures_util::stream::iter(heights)
+        .map(move |height| {
+            let snap = snap.clone();
+            async move {
+                let old_snap = upstream(snap.get_older(height.into())).await?;
+                let proposer_reward =
+                    upstream(old_snap.get_coin(CoinID::proposer_reward(height.into()))).await?;
+                let block = upstream(old_snap.current_block()).await?;
+                let mut dosc_erg_spent = 0;
+                for tx in block
+                    .transactions
+                    .iter()
+                    .filter(|tx| tx.kind == TxKind::DoscMint)
+                {
+                    for coinid in tx.inputs.iter() {
+                        let spent = upstream(old_snap.get_coin_spent_here(*coinid))
+                            .await?
+                            .context("spent coin not found")?;
+                        if spent.coin_data.denom == Denom::Erg {
+                            dosc_erg_spent += spent.coin_data.value.0;
+                        }
+                    }
+                }
+                anyhow::Ok(BlockData {
+                    block,
+                    proposer_reward,
+                    dosc_erg_spent,
+                })
+            }
+        })
+        .buffered(FETCH_CONCURRENCY)
+}
+
+/// Runs every migration that has not run yet. Whatever they add is queued for backfilling up to the height that was already indexed, while the rest of the index carries on from there.
+fn migrate(conn: &mut rusqlite::Connection) -> anyhow::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS chainidx_progress (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            height INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS chainidx_version (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            version INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS chainidx_backfills (
+            part TEXT PRIMARY KEY,
+            height INTEGER NOT NULL,
+            until INTEGER NOT NULL
+        );",
+    )?;
+    let dbtx = conn.transaction()?;
+    let version = dbtx
+        .query_row(
+            "SELECT version FROM chainidx_version WHERE id = 0",
+            [],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let indexed = dbtx
+        .query_row(
+            "SELECT height FROM chainidx_progress WHERE id = 0",
+            [],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = i as i64 + 1;
+        log::info!("migrating chain index schema to version {}", version);
+        dbtx.execute_batch(migration.sql)?;
+        if let Some(indexed) = indexed {
+            for part in migration.backfill {
+                dbtx.execute(
+                    "INSERT OR REPLACE INTO chainidx_backfills (part, height, until) VALUES (?1, 0, ?2)",
+                    params![part.as_str(), indexed],
+                )?;
+            }
+        }
+        dbtx.execute(
+            "INSERT OR REPLACE INTO chainidx_version (id, version) VALUES (0, ?1)",
+            params![version],
+        )?;
+    }
+    dbtx.commit()?;
+    Ok(())
+}
+
+/// Records the stakes of the genesis state at height 0. They are only known for the standard networks.
+fn index_genesis_stakes(conn: &mut rusqlite::Connection, network: NetID) -> anyhow::Result<()> {
+    let genesis = match network {
+        NetID::Mainnet => GenesisConfig::std_mainnet(),
+        NetID::Testnet => GenesisConfig::std_testnet(),
+        _ => return Ok(()),
+    };
+    let dbtx = conn.transaction()?;
+    for (txhash, doc) in genesis.stakes.iter() {
+        dbtx.execute(
+            "INSERT OR IGNORE INTO stakes (txhash, height, pubkey, e_start, e_post_end, syms_staked) VALUES (?1, 0, ?2, ?3, ?4, ?5)",
+            params![
+                txhash.to_string(),
+                doc.pubkey.to_string(),
+                sql_int(doc.e_start.into()),
+                sql_int(doc.e_post_end.into()),
+                doc.syms_staked.0.to_string()
+            ],
+        )?;
+    }
+    dbtx.commit()?;
+    Ok(())
+}
+
+fn parse_reveal(height: i64, txhash: &str) -> anyhow::Result<Reveal> {
+    Ok(Reveal {
+        height: BlockHeight(height as u64),
+        txhash: TxHash(HashVal::from_str(txhash)?),
+    })
+}
+
+/// Records what the chain index tracks about a single block, for the given parts.
+fn index_block(
+    dbtx: &rusqlite::Transaction,
+    data: &BlockData,
+    parts: &[Part],
+) -> anyhow::Result<()> {
+    let block = &data.block;
+    let height = block.header.height.0 as i64;
+    if parts.contains(&Part::ProposerRewards) {
+        if let Some(reward) = data.proposer_reward.as_ref() {
+            dbtx.execute(
+                "INSERT OR REPLACE INTO proposer_rewards (height, covhash, reward) VALUES (?1, ?2, ?3)",
+                params![
+                    height,
+                    reward.coin_data.covhash.0.to_string(),
+                    sql_int(reward.coin_data.value.0)
+                ],
+            )?;
+        }
+    }
+    if parts.contains(&Part::BlockHashes) {
+        dbtx.execute(
+            "INSERT OR REPLACE INTO block_hashes (blkhash, height) VALUES (?1, ?2)",
+            params![block.header.hash().to_string(), height],
+        )?;
+    }
+    if parts.contains(&Part::BlockStats) {
+        let fees: u128 = block.transactions.iter().map(|tx| tx.fee.0).sum();
+        let dosc_erg_created: u128 = block
+            .transactions
+            .iter()
+            .filter(|tx| tx.kind == TxKind::DoscMint)
+            .flat_map(|tx| tx.outputs.iter())
+            .filter(|output| output.denom == Denom::Erg)
+            .map(|output| output.value.0)
+            .sum();
+        let dosc_minted = dosc_erg_created.saturating_sub(data.dosc_erg_spent);
+        dbtx.execute(
+            "INSERT OR REPLACE INTO block_stats (height, tx_count, fees, dosc_minted, fee_multiplier) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                height,
+                block.transactions.len() as i64,
+                sql_int(fees),
+                sql_int(dosc_minted),
+                sql_int(block.header.fee_multiplier)
+            ],
+        )?;
+    }
+    for tx in block.transactions.iter() {
+        let txhash = tx.hash_nosigs().to_string();
+        if parts.contains(&Part::Transactions) {
+            index_transaction(dbtx, height, &txhash, tx)?;
+        }
+        if parts.contains(&Part::Covenants) {
+            for covenant in tx.covenants.iter() {
+                let covhash = tmelcrypt::hash_single(covenant).to_string();
+                dbtx.execute(
+                    "INSERT OR IGNORE INTO covenants (covhash, bytes, first_height, first_txhash) VALUES (?1, ?2, ?3, ?4)",
+                    params![covhash, covenant.to_vec(), height, txhash],
+                )?;
+                dbtx.execute(
+                    "INSERT OR IGNORE INTO covenant_reveals (covhash, height, txhash) VALUES (?1, ?2, ?3)",
+                    params![covhash, height, txhash],
+                )?;
+            }
+        }
+        if parts.contains(&Part::Stakes) && tx.kind == TxKind::Stake {
+            // the validity rules should guarantee a well-formed stake document, so one that does not decode means the index is missing a stake
+            match stdcode::deserialize::<StakeDoc>(&tx.data) {
+                Ok(doc) => {
+                    dbtx.execute(
+                        "INSERT OR IGNORE INTO stakes (txhash, height, pubkey, e_start, e_post_end, syms_staked) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            txhash,
+                            height,
+                            doc.pubkey.to_string(),
+                            sql_int(doc.e_start.into()),
+                            sql_int(doc.e_post_end.into()),
+                            doc.syms_staked.0.to_string()
+                        ],
+                    )?;
+                }
+                Err(err) => log::warn!(
+                    "stake transaction {} at height {} has an invalid stake document: {:?}",
+                    txhash,
+                    height,
+                    err
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records a transaction's searchable fields.
+fn index_transaction(
+    dbtx: &rusqlite::Transaction,
+    height: i64,
+    txhash: &str,
+    tx: &Transaction,
+) -> anyhow::Result<()> {
+    dbtx.execute(
+        "INSERT OR REPLACE INTO transactions (txhash, height, kind, fee, covenant_count) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            txhash,
+            height,
+            tx.kind.to_string(),
+            sql_int(tx.fee.0),
+            tx.covenants.len() as i64
+        ],
+    )?;
+    let mut denoms: BTreeMap<String, u128> = BTreeMap::new();
+    for output in tx.outputs.iter() {
+        // outputs creating a new token are stored under the token's actual denomination
+        let denom = match output.denom {
+            Denom::NewCustom => Denom::Custom(tx.hash_nosigs()),
+            denom => denom,
+        };
+        *denoms.entry(denom.to_string()).or_default() += output.value.0;
+        dbtx.execute(
+            "INSERT OR IGNORE INTO tx_addresses (txhash, height, covhash) VALUES (?1, ?2, ?3)",
+            params![txhash, height, output.covhash.0.to_string()],
+        )?;
+    }
+    for (denom, value) in denoms {
+        dbtx.execute(
+            "INSERT OR REPLACE INTO tx_denoms (txhash, height, denom, value) VALUES (?1, ?2, ?3, ?4)",
+            params![txhash, height, denom, sql_int(value)],
+        )?;
+    }
+    // every input's covenant has to be revealed, so this covers the addresses being spent from
+    for covenant in tx.covenants.iter() {
+        dbtx.execute(
+            "INSERT OR IGNORE INTO tx_addresses (txhash, height, covhash) VALUES (?1, ?2, ?3)",
+            params![txhash, height, tmelcrypt::hash_single(covenant).to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+/// The mean gap between the newest and oldest of some `(height, seen)` arrivals, newest first.
+fn mean_interval(arrivals: &[(i64, i64)]) -> Option<f64> {
+    let (newest, oldest) = (arrivals.first()?, arrivals.last()?);
+    let blocks = newest.0 - oldest.0;
+    if blocks < MIN_INTERVAL_SPAN as i64 {
+        return None;
+    }
+    Some((newest.1 - oldest.1) as f64 / blocks as f64)
+}
+
+/// Clamps a number into an SQLite integer. No real amount comes anywhere near the limit, but epochs can be set arbitrarily far in the future.
+fn sql_int(value: u128) -> i64 {
+    value.min(i64::MAX as u128) as i64
+}
+
+/// The first and last heights of an epoch, or None if they are beyond any possible height. Block 0 is the genesis state, which is never indexed.
+fn epoch_bounds(epoch: u64) -> Option<(BlockHeight, BlockHeight)> {
+    let start = epoch.checked_mul(STAKE_EPOCH)?;
+    let last = epoch.checked_add(1)?.checked_mul(STAKE_EPOCH)? - 1;
+    Some((BlockHeight(start.max(1)), BlockHeight(last)))
+}
+
+/// Loads a stored summary, or computes one from the indexed blocks, storing it if the epoch is over. Returns None if the epoch has not started yet.
+fn epoch_summary(
+    conn: &rusqlite::Connection,
+    epoch: u64,
+    indexed: BlockHeight,
+) -> anyhow::Result<Option<EpochSummary>> {
+    let stored = conn
+        .query_row(
+            "SELECT summary FROM epoch_summaries WHERE epoch = ?1",
+            params![epoch as i64],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    if let Some(stored) = stored {
+        return Ok(Some(serde_json::from_str(&stored)?));
+    }
+
+    let (start_height, last_height) = match epoch_bounds(epoch) {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+    if start_height > indexed {
+        return Ok(None);
+    }
+    let end_height = last_height.min(indexed);
+    let range = params![start_height.0 as i64, end_height.0 as i64];
+    let (transactions, fees, dosc_minted) = conn.query_row(
+        "SELECT COALESCE(SUM(tx_count), 0), COALESCE(SUM(fees), 0), COALESCE(SUM(dosc_minted), 0) FROM block_stats WHERE height BETWEEN ?1 AND ?2",
+        range,
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+    )?;
+    let proposer_rewards: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(reward), 0) FROM proposer_rewards WHERE height BETWEEN ?1 AND ?2",
+        range,
+        |row| row.get(0),
+    )?;
+    let fee_multiplier = |height: BlockHeight| {
+        conn.query_row(
+            "SELECT fee_multiplier FROM block_stats WHERE height = ?1",
+            params![height.0 as i64],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()
+        .map(|m| m.unwrap_or_default() as u128)
+    };
+
+    let mut new_stakes = 0;
+    let mut syms_newly_staked = 0u128;
+    let mut stmt = conn.prepare("SELECT syms_staked FROM stakes WHERE height BETWEEN ?1 AND ?2")?;
+    for syms in stmt.query_map(range, |row| row.get::<_, String>(0))? {
+        new_stakes += 1;
+        syms_newly_staked += syms?.parse::<u128>()?;
+    }
+    let mut syms_staked = 0u128;
+    let mut stmt = conn.prepare(
+        "SELECT syms_staked FROM stakes WHERE height <= ?1 AND e_start <= ?2 AND e_post_end > ?2",
+    )?;
+    for syms in stmt.query_map(params![end_height.0 as i64, epoch as i64], |row| {
+        row.get::<_, String>(0)
+    })? {
+        syms_staked += syms?.parse::<u128>()?;
+    }
+
+    let summary = EpochSummary {
+        epoch,
+        start_height,
+        end_height,
+        finalized: end_height == last_height,
+        transactions: transactions as u64,
+        fees: CoinValue(fees as u128),
+        proposer_rewards: CoinValue(proposer_rewards as u128),
+        dosc_minted: CoinValue(dosc_minted as u128),
+        new_stakes,
+        syms_newly_staked: CoinValue(syms_newly_staked),
+        syms_staked: CoinValue(syms_staked),
+        start_fee_multiplier: fee_multiplier(start_height)?,
+        end_fee_multiplier: fee_multiplier(end_height)?,
+    };
+    // summaries are not stored while a backfill could still change them
+    let backfilling: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM chainidx_backfills)",
+        [],
+        |row| row.get(0),
+    )?;
+    if summary.finalized && !backfilling {
+        conn.execute(
+            "INSERT OR REPLACE INTO epoch_summaries (epoch, summary) VALUES (?1, ?2)",
+            params![epoch as i64, serde_json::to_string(&summary)?],
+        )?;
+    }
+    Ok(Some(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_bounds_never_overflow() {
+        assert_eq!(
+            epoch_bounds(0),
+            Some((BlockHeight(1), BlockHeight(STAKE_EPOCH - 1)))
+        );
+        assert_eq!(
+            epoch_bounds(2),
+            Some((
+                BlockHeight(2 * STAKE_EPOCH),
+                BlockHeight(3 * STAKE_EPOCH - 1)
+            ))
+        );
+        assert_eq!(epoch_bounds(u64::MAX), None);
+        assert_eq!(epoch_bounds(u64::MAX / STAKE_EPOCH), None);
+    }
+
+    fn backfills(conn: &rusqlite::Connection) -> Vec<(String, i64)> {
+        let mut stmt = conn
+            .prepare("SELECT part, until FROM chainidx_backfills ORDER BY rowid")
+            .unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn fresh_databases_need_no_backfill() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .query_row("SELECT version FROM chainidx_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+        assert!(backfills(&conn).is_empty());
+    }
+
+    #[test]
+    fn migrations_only_backfill_what_they_add() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        // roll back to a database indexed up to height 1000 before the hash tables were added
+        conn.execute_batch(
+            "DROP TABLE block_hashes;
+            DROP TABLE transactions;
+            UPDATE chainidx_version SET version = 4;
+            INSERT INTO chainidx_progress (id, height) VALUES (0, 1000);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(
+            backfills(&conn),
+            vec![
+                ("block_hashes".to_string(), 1000),
+                ("transactions".to_string(), 1000)
+            ]
+        );
+        let progress: i64 = conn
+            .query_row("SELECT height FROM chainidx_progress", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(progress, 1000);
+        // migrating again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(backfills(&conn).len(), 2);
+    }
+
+    #[test]
+    fn block_interval_needs_enough_arrivals() {
+        assert_eq!(mean_interval(&[]), None);
+        assert_eq!(mean_interval(&[(110, 1300), (100, 1000)]), None);
+        // a gap in the arrivals, e.g. from a restart, does not skew the mean
+        let arrivals = [(150, 2500), (149, 2470), (110, 1300), (100, 1000)];
+        assert_eq!(mean_interval(&arrivals), Some(30.0));
+    }
+
+    #[test]
+    fn only_finalized_summaries_are_stored() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let insert = |epoch: i64, finalized: bool| {
+            conn.execute(
+                "INSERT INTO epoch_summaries (epoch, summary) VALUES (?1, ?2)",
+                params![
+                    epoch,
+                    serde_json::json!({ "finalized": finalized }).to_string()
+                ],
+            )
+        };
+        assert!(insert(0, true).is_ok());
+        assert!(insert(1, false).is_err());
+    }
+}
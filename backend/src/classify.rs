@@ -0,0 +1,338 @@
+use std::convert::TryFrom;
+
+use melstructs::{Address, BlockHeight};
+use melvm::{opcode::OpCode, Covenant};
+use serde::Serialize;
+use tmelcrypt::Ed25519PK;
+
+/// Heap address of the spending transaction.
+const HADDR_SPENDER_TX: u16 = 0;
+/// Heap address of the spending transaction's hash.
+const HADDR_SPENDER_TXHASH: u16 = 1;
+/// Heap address of the header of the block before the spending one.
+const HADDR_LAST_HEADER: u16 = 10;
+/// Index of the `sigs` field within a transaction.
+const TX_SIGS_FIELD: u32 = 6;
+/// Index of the `height` field within a header.
+const HEADER_HEIGHT_FIELD: u32 = 2;
+
+/// What kind of covenant an address is locked by, along with whatever parameters could be extracted from it.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CovenantClass {
+    /// The standard single-signature covenant, which checks the signature at the same index as the input being unlocked.
+    Ed25519 { pubkey: Ed25519PK },
+    /// The older single-signature covenant, which always checks the first signature.
+    Ed25519Legacy { pubkey: Ed25519PK },
+    /// A threshold multisig, where signature `i` must be by public key `i`.
+    Multisig {
+        threshold: u64,
+        pubkeys: Vec<Ed25519PK>,
+    },
+    /// The standard single-signature covenant, which additionally needs the block before the spending one to be above `unlock_height`.
+    Timelock {
+        unlock_height: BlockHeight,
+        pubkey: Ed25519PK,
+    },
+    /// The address that destroys coins sent to it.
+    Burn,
+    /// A covenant that matches no known template.
+    Custom,
+    /// The covenant has never been revealed on-chain, so nothing is known about it.
+    Unrevealed,
+}
+
+/// Classifies an address, given its covenant if known.
+pub fn classify_address(address: Address, covenant: Option<&[u8]>) -> CovenantClass {
+    if address == Address::coin_destroy() {
+        return CovenantClass::Burn;
+    }
+    match covenant {
+        Some(covenant) => classify(covenant),
+        None => CovenantClass::Unrevealed,
+    }
+}
+
+/// Classifies covenant bytes, by comparing their instructions against every template. The parameters of a template, like public keys and thresholds, are pulled out of the instructions first, so that only covenants built by a template, for some parameters, match it.
+pub fn classify(covenant: &[u8]) -> CovenantClass {
+    let ops = match Covenant::from_bytes(covenant) {
+        Ok(parsed) => parsed.to_ops(),
+        Err(_) => return CovenantClass::Custom,
+    };
+    let shape = Shape::of(&ops);
+    let pubkeys: Vec<Ed25519PK> = shape
+        .immediates
+        .iter()
+        .filter_map(|op| match op {
+            OpCode::PushB(bytes) => Ed25519PK::from_bytes(bytes),
+            _ => None,
+        })
+        .collect();
+    let ints: Vec<u64> = shape
+        .immediates
+        .iter()
+        .filter_map(|op| match op {
+            OpCode::PushI(n) => u64::try_from(*n).ok(),
+            _ => None,
+        })
+        .collect();
+
+    let mut candidates = vec![];
+    if let Some(&pubkey) = pubkeys.first() {
+        candidates.push(CovenantClass::Ed25519 { pubkey });
+        candidates.push(CovenantClass::Ed25519Legacy { pubkey });
+        if let Some(&unlock_height) = ints.first() {
+            candidates.push(CovenantClass::Timelock {
+                unlock_height: BlockHeight(unlock_height),
+                pubkey,
+            });
+        }
+    }
+    if let Some(&threshold) = ints.first() {
+        candidates.push(CovenantClass::Multisig {
+            threshold,
+            pubkeys: pubkeys.clone(),
+        });
+    }
+    candidates
+        .into_iter()
+        .find(|class| template(class).map(|t| Shape::of(&t) == shape) == Some(true))
+        .unwrap_or(CovenantClass::Custom)
+}
+
+/// The instructions of a covenant with their immediates pulled out, so that covenants built by the same template for different parameters have the same `ops`.
+#[derive(PartialEq, Eq, Debug)]
+struct Shape {
+    /// The instructions, with every pushed integer replaced by 0 and every pushed byte string by an empty one.
+    ops: Vec<OpCode>,
+    /// The pushed integers and byte strings, in order. Integers are always in the uncompressed form, since both forms push the same value.
+    immediates: Vec<OpCode>,
+}
+
+impl Shape {
+    fn of(ops: &[OpCode]) -> Self {
+        let mut immediates = vec![];
+        let ops = ops
+            .iter()
+            .map(|op| match op {
+                OpCode::PushI(n) | OpCode::PushIC(n) => {
+                    immediates.push(OpCode::PushI(*n));
+                    OpCode::PushI(0u32.into())
+                }
+                OpCode::PushB(bytes) => {
+                    immediates.push(OpCode::PushB(bytes.clone()));
+                    OpCode::PushB(vec![])
+                }
+                op => op.clone(),
+            })
+            .collect();
+        Self { ops, immediates }
+    }
+}
+
+/// The instructions that a template builds for the parameters of a class, or None if the parameters are invalid for it.
+fn template(class: &CovenantClass) -> Option<Vec<OpCode>> {
+    match class {
+        CovenantClass::Ed25519 { pubkey } => Some(Covenant::std_ed25519_pk_new(*pubkey).to_ops()),
+        CovenantClass::Ed25519Legacy { pubkey } => {
+            Some(Covenant::std_ed25519_pk_legacy(*pubkey).to_ops())
+        }
+        CovenantClass::Timelock {
+            unlock_height,
+            pubkey,
+        } => {
+            let mut ops = vec![
+                OpCode::PushI(unlock_height.0.into()),
+                OpCode::PushI(HEADER_HEIGHT_FIELD.into()),
+                OpCode::LoadImm(HADDR_LAST_HEADER),
+                OpCode::VRef,
+                OpCode::Gt,
+            ];
+            ops.extend(Covenant::std_ed25519_pk_new(*pubkey).to_ops());
+            ops.push(OpCode::And);
+            Some(ops)
+        }
+        CovenantClass::Multisig { threshold, pubkeys } => {
+            if *threshold == 0 || *threshold > pubkeys.len() as u64 {
+                return None;
+            }
+            // pushes the threshold, counts the valid signatures, and checks that the count is at least the threshold
+            let mut ops = vec![OpCode::PushI((*threshold).into())];
+            for (i, pubkey) in pubkeys.iter().enumerate() {
+                ops.extend(vec![
+                    OpCode::PushI((i as u64).into()),
+                    OpCode::PushI(TX_SIGS_FIELD.into()),
+                    OpCode::LoadImm(HADDR_SPENDER_TX),
+                    OpCode::VRef,
+                    OpCode::VRef,
+                    OpCode::PushB(pubkey.0.to_vec()),
+                    OpCode::LoadImm(HADDR_SPENDER_TXHASH),
+                    OpCode::SigEOk(32),
+                ]);
+                if i > 0 {
+                    ops.push(OpCode::Add);
+                }
+            }
+            ops.extend(vec![OpCode::PushI(1u32.into()), OpCode::Add, OpCode::Gt]);
+            Some(ops)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use melstructs::{
+        CoinData, CoinDataHeight, CoinID, CoinValue, Denom, Header, NetID, Transaction, TxHash,
+    };
+    use melvm::CovenantEnv;
+    use tmelcrypt::{Ed25519SK, HashVal};
+
+    use super::*;
+
+    fn covenant(class: &CovenantClass) -> Vec<u8> {
+        Covenant::from_ops(&template(class).unwrap())
+            .to_bytes()
+            .to_vec()
+    }
+
+    /// Whether the covenant accepts a transaction spending it as the first input, in a block after `last_height`.
+    fn accepts(class: &CovenantClass, transaction: &Transaction, last_height: u64) -> bool {
+        let covenant = Covenant::from_ops(&template(class).unwrap());
+        let env = CovenantEnv {
+            parent_coinid: CoinID {
+                txhash: TxHash(HashVal::default()),
+                index: 0,
+            },
+            parent_cdh: CoinDataHeight {
+                coin_data: CoinData {
+                    covhash: covenant.hash(),
+                    value: CoinValue(1),
+                    denom: Denom::Mel,
+                    additional_data: Default::default(),
+                },
+                height: BlockHeight(1),
+            },
+            spender_index: 0,
+            last_header: Header {
+                network: NetID::Testnet,
+                previous: HashVal::default(),
+                height: BlockHeight(last_height),
+                history_hash: HashVal::default(),
+                coins_hash: HashVal::default(),
+                transactions_hash: HashVal::default(),
+                fee_pool: CoinValue(0),
+                fee_multiplier: 1,
+                dosc_speed: 1,
+                pools_hash: HashVal::default(),
+                stakes_hash: HashVal::default(),
+            },
+        };
+        covenant
+            .execute(transaction, Some(env))
+            .map(|v| v.into_bool())
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn classifies_ed25519() {
+        let pubkey = Ed25519SK::generate().to_public();
+        assert_eq!(
+            classify(&Covenant::std_ed25519_pk_new(pubkey).to_bytes()),
+            CovenantClass::Ed25519 { pubkey }
+        );
+    }
+
+    #[test]
+    fn classifies_ed25519_legacy() {
+        let pubkey = Ed25519SK::generate().to_public();
+        assert_eq!(
+            classify(&Covenant::std_ed25519_pk_legacy(pubkey).to_bytes()),
+            CovenantClass::Ed25519Legacy { pubkey }
+        );
+    }
+
+    #[test]
+    fn classifies_multisig() {
+        let sks: Vec<Ed25519SK> = (0..3).map(|_| Ed25519SK::generate()).collect();
+        let pubkeys: Vec<Ed25519PK> = sks.iter().map(|sk| sk.to_public()).collect();
+        let two_of_three = CovenantClass::Multisig {
+            threshold: 2,
+            pubkeys: pubkeys.clone(),
+        };
+        assert_eq!(classify(&covenant(&two_of_three)), two_of_three);
+
+        // signatures 0 and 2 are good, but 1 is by the wrong key
+        let transaction = Transaction::default()
+            .signed_ed25519(sks[0])
+            .signed_ed25519(sks[2])
+            .signed_ed25519(sks[2]);
+        assert!(accepts(&two_of_three, &transaction, 1));
+        let three_of_three = CovenantClass::Multisig {
+            threshold: 3,
+            pubkeys: pubkeys.clone(),
+        };
+        assert!(!accepts(&three_of_three, &transaction, 1));
+
+        // a threshold above the number of keys is not something the template builds
+        let mut ops = template(&three_of_three).unwrap();
+        ops[0] = OpCode::PushI(4u32.into());
+        assert_eq!(
+            classify(&Covenant::from_ops(&ops).to_bytes()),
+            CovenantClass::Custom
+        );
+    }
+
+    #[test]
+    fn classifies_timelock() {
+        let sk = Ed25519SK::generate();
+        let timelock = CovenantClass::Timelock {
+            unlock_height: BlockHeight(100),
+            pubkey: sk.to_public(),
+        };
+        assert_eq!(classify(&covenant(&timelock)), timelock);
+
+        let transaction = Transaction::default().signed_ed25519(sk);
+        assert!(!accepts(&timelock, &transaction, 100));
+        assert!(accepts(&timelock, &transaction, 101));
+    }
+
+    #[test]
+    fn compressed_integers_match() {
+        let pubkey = Ed25519SK::generate().to_public();
+        let mut ops = Covenant::std_ed25519_pk_new(pubkey).to_ops();
+        ops[1] = OpCode::PushIC(TX_SIGS_FIELD.into());
+        assert_eq!(
+            classify(&Covenant::from_ops(&ops).to_bytes()),
+            CovenantClass::Ed25519 { pubkey }
+        );
+    }
+
+    #[test]
+    fn falls_back_to_custom() {
+        assert_eq!(
+            classify(&Covenant::always_true().to_bytes()),
+            CovenantClass::Custom
+        );
+        // a standard covenant with an extra instruction tacked on is not standard
+        let pubkey = Ed25519SK::generate().to_public();
+        let mut ops = Covenant::std_ed25519_pk_new(pubkey).to_ops();
+        ops.push(OpCode::Noop);
+        assert_eq!(
+            classify(&Covenant::from_ops(&ops).to_bytes()),
+            CovenantClass::Custom
+        );
+        // as is one with a different constant where a template has a fixed one
+        let mut ops = Covenant::std_ed25519_pk_new(pubkey).to_ops();
+        ops[1] = OpCode::PushI(5u32.into());
+        assert_eq!(
+            classify(&Covenant::from_ops(&ops).to_bytes()),
+            CovenantClass::Custom
+        );
+        assert_eq!(classify(&[0xfe]), CovenantClass::Custom);
+        assert_eq!(
+            classify_address(Address::coin_destroy(), None),
+            CovenantClass::Burn
+        );
+    }
+}
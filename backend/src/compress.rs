@@ -0,0 +1,148 @@
+use std::io;
+
+use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use futures_util::{io::AsyncRead, AsyncReadExt, TryStreamExt};
+use warp::{
+    hyper::{
+        body::HttpBody,
+        header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, VARY},
+        Body, Response, StatusCode,
+    },
+    Reply,
+};
+
+/// Bodies smaller than this are not worth compressing.
+const MIN_COMPRESSED_SIZE: u64 = 1024;
+
+/// How big the chunks of a compressed body are.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A compression algorithm that responses can be encoded with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// All supported encodings, most preferred first.
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the encoding that an `Accept-Encoding` header ranks highest. Ties go to the one we prefer.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let ranked = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect::<Vec<_>>();
+    // an encoding with a quality of 0 is refused, even if "*" would allow it
+    let quality = |encoding: Encoding| {
+        ranked
+            .iter()
+            .find(|(name, _)| name == encoding.name())
+            .or_else(|| ranked.iter().find(|(name, _)| name == "*"))
+            .map(|(_, q)| *q)
+            .filter(|q| *q > 0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in Encoding::ALL {
+        if let Some(q) = quality(encoding) {
+            if best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((encoding, q));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses a reply with the best encoding the client accepts, streaming the compressed body.
+pub fn compress(accept_encoding: Option<String>, reply: impl Reply) -> Response<Body> {
+    let mut response = reply.into_response();
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let encoding = match accept_encoding.as_deref().and_then(negotiate) {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::NO_CONTENT
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    if body
+        .size_hint()
+        .exact()
+        .map(|len| len < MIN_COMPRESSED_SIZE)
+        .unwrap_or(false)
+    {
+        return Response::from_parts(parts, body);
+    }
+    let reader = TryStreamExt::map_err(body, io::Error::other).into_async_read();
+    let encoder: Box<dyn AsyncRead + Send + Unpin> = match encoding {
+        Encoding::Brotli => Box::new(BrotliEncoder::new(reader)),
+        Encoding::Zstd => Box::new(ZstdEncoder::new(reader)),
+        Encoding::Gzip => Box::new(GzipEncoder::new(reader)),
+    };
+    let chunks = futures_util::stream::try_unfold(encoder, |mut encoder| async move {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let n = encoder.read(&mut chunk).await?;
+        if n == 0 {
+            return io::Result::Ok(None);
+        }
+        chunk.truncate(n);
+        Ok(Some((chunk, encoder)))
+    });
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    // the compressed bytes differ, but the content is the same
+    if let Some(etag) = parts.headers.get(ETAG).cloned() {
+        if let Ok(etag) = etag.to_str() {
+            if !etag.starts_with("W/") {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    parts.headers.insert(ETAG, weak);
+                }
+            }
+        }
+    }
+    Response::from_parts(parts, Body::wrap_stream(chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("identity"), None);
+    }
+}
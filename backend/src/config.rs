@@ -0,0 +1,511 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use melstructs::NetID;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+use crate::{cors::CorsConfig, search::MIN_PREFIX_LEN};
+
+/// Command-line flags. Every flag that takes a value can also be set with an environment variable, and overrides the config file.
+#[derive(StructOpt, Debug, Default)]
+pub struct Args {
+    #[structopt(long, env = "MELSCAN_CONFIG")]
+    /// A TOML config file to read settings from.
+    config: Option<PathBuf>,
+
+    #[structopt(long, env = "MELSCAN_LISTEN", use_delimiter = true)]
+    /// Where to listen for incoming REST API calls. Can be given more than once.
+    listen: Vec<SocketAddr>,
+
+    #[structopt(long, env = "MELSCAN_CONNECT")]
+    /// A full node to connect to
+    connect: Option<SocketAddr>,
+
+    #[structopt(long, env = "MELSCAN_NETWORK")]
+    /// The custom network to connect to
+    network: Option<NetID>,
+
+    #[structopt(long, env = "MELSCAN_TESTNET")]
+    /// Whether or not the block explorer is connected to a testnet node. `--testnet` alone means `--testnet true`.
+    testnet: Option<Option<bool>>,
+
+    #[structopt(long, env = "MELSCAN_BLKIDX_DB")]
+    /// If set, indexes blocks and saves them to the given location.
+    blkidx_db: Option<PathBuf>,
+
+    #[structopt(long, env = "MELSCAN_LOCAL_DB")]
+    /// Where to store melscan's own state, such as watchlists, the chain index and the block cache. If not set, watchlists are kept in memory and the chain index and block cache are disabled.
+    local_db: Option<PathBuf>,
+
+    #[structopt(long, env = "MELSCAN_WEBHOOK_URL")]
+    /// Default webhook URL that watchlist events are POSTed to.
+    webhook_url: Option<String>,
+
+    #[structopt(long, env = "MELSCAN_MIN_PREFIX_LEN")]
+    /// The fewest hex digits of a transaction or block hash that can be resolved as a prefix, at least 4. Defaults to 8.
+    min_prefix_len: Option<usize>,
+
+    #[structopt(long, env = "MELSCAN_RATE_LIMIT")]
+    /// Requests per minute that each IP address can make. Zero disables the limit. Defaults to 600.
+    rate_limit: Option<u32>,
+
+    #[structopt(long, env = "MELSCAN_EXPENSIVE_RATE_LIMIT")]
+    /// Requests per minute that each IP address can make to expensive routes, such as crawls, graphs, leaderboards and addresses. Zero disables the limit. Defaults to 60.
+    expensive_rate_limit: Option<u32>,
+
+    #[structopt(long, env = "MELSCAN_API_KEY_RATE_LIMIT")]
+    /// Requests per minute that each API key can make. Zero disables the limit. Defaults to 6000.
+    api_key_rate_limit: Option<u32>,
+
+    #[structopt(long, env = "MELSCAN_API_KEY_EXPENSIVE_RATE_LIMIT")]
+    /// Requests per minute that each API key can make to expensive routes. Zero disables the limit. Defaults to 600.
+    api_key_expensive_rate_limit: Option<u32>,
+
+    #[structopt(long, env = "MELSCAN_ADMIN_KEY", use_delimiter = true)]
+    /// An API key with the admin scope, for creating other keys through the admin API. Can be given more than once.
+    admin_key: Vec<String>,
+
+    #[structopt(long, env = "MELSCAN_TRUST_FORWARDED_FOR")]
+    /// Whether to rate-limit clients by the first address in `X-Forwarded-For`, when running behind a reverse proxy. `--trust-forwarded-for` alone means `--trust-forwarded-for true`.
+    trust_forwarded_for: Option<Option<bool>>,
+
+    #[structopt(long, env = "MELSCAN_UPSTREAM_CONCURRENCY")]
+    /// How many calls to the full node can be in flight at once, across all requests. Defaults to 64.
+    upstream_concurrency: Option<usize>,
+
+    #[structopt(long, env = "MELSCAN_REQUEST_TIMEOUT")]
+    /// Seconds a request can take before it fails with a timeout. Defaults to 30.
+    request_timeout: Option<u64>,
+
+    #[structopt(long, env = "MELSCAN_EXPENSIVE_REQUEST_TIMEOUT")]
+    /// Seconds a request to an expensive route can take before it fails with a timeout. Defaults to 120.
+    expensive_request_timeout: Option<u64>,
+
+    #[structopt(long, env = "MELSCAN_CORS_ORIGIN", use_delimiter = true)]
+    /// An origin that browsers may call the public API from, or `*` for any. Can be given more than once. Defaults to any origin.
+    cors_origin: Vec<String>,
+
+    #[structopt(long, env = "MELSCAN_LOG")]
+    /// Which logs to print, as a `tracing` filter directive. `RUST_LOG` takes precedence if set.
+    log: Option<String>,
+
+    #[structopt(long, env = "MELSCAN_LOG_ANSI")]
+    /// Whether to color the logs. `--log-ansi` alone means `--log-ansi true`.
+    log_ansi: Option<Option<bool>>,
+}
+
+/// Melscan's configuration: the config file, if any, with command-line flags and environment variables applied on top.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub upstream: UpstreamConfig,
+    pub storage: StorageConfig,
+    pub cache: CacheConfig,
+    pub rate_limits: RateLimitConfig,
+    pub auth: AuthConfig,
+    pub watchlist: WatchlistConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Where to listen for incoming REST API calls.
+    pub listen: Vec<SocketAddr>,
+    /// The fewest hex digits of a transaction or block hash that can be resolved as a prefix.
+    pub min_prefix_len: usize,
+    /// Whether to tell clients apart by the first address in `X-Forwarded-For`.
+    pub trust_forwarded_for: bool,
+    /// Seconds a request can take before it fails with a timeout.
+    pub request_timeout: u64,
+    /// Seconds a request to an expensive route can take.
+    pub expensive_request_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            min_prefix_len: 8,
+            trust_forwarded_for: false,
+            request_timeout: 30,
+            expensive_request_timeout: 120,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The full node to connect to.
+    pub connect: Option<SocketAddr>,
+    /// A custom network to connect to, whose latest snapshot is trusted blindly.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub network: Option<NetID>,
+    /// Whether the full node is on the testnet.
+    pub testnet: bool,
+    /// How many calls to the full node can be in flight at once.
+    pub concurrency: usize,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            connect: None,
+            network: None,
+            testnet: false,
+            concurrency: 64,
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// The network that the full node is on.
+    pub fn netid(&self) -> NetID {
+        if let Some(network) = self.network {
+            network
+        } else if self.testnet {
+            NetID::Testnet
+        } else {
+            NetID::Mainnet
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Where the block indexer keeps its database. Unset disables the indexer.
+    pub blkidx_db: Option<PathBuf>,
+    /// Where melscan keeps its own state. Unset keeps everything in memory.
+    pub local_db: Option<PathBuf>,
+}
+
+/// Sizes of the in-memory caches, in entries, and how many seconds entries live.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub address_capacity: u64,
+    pub address_ttl: u64,
+    pub leaderboard_capacity: u64,
+    pub leaderboard_ttl: u64,
+    pub block_capacity: u64,
+    pub crawl_capacity: u64,
+    pub spend_capacity: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            address_capacity: 10000,
+            address_ttl: 30,
+            leaderboard_capacity: 10000,
+            leaderboard_ttl: 1800,
+            block_capacity: 100,
+            crawl_capacity: 10000,
+            spend_capacity: 100000,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn address_ttl(&self) -> Duration {
+        Duration::from_secs(self.address_ttl)
+    }
+
+    pub fn leaderboard_ttl(&self) -> Duration {
+        Duration::from_secs(self.leaderboard_ttl)
+    }
+}
+
+/// Requests per minute. Zero disables a limit.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub per_ip: u32,
+    pub expensive_per_ip: u32,
+    pub per_api_key: u32,
+    pub expensive_per_api_key: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_ip: 600,
+            expensive_per_ip: 60,
+            per_api_key: 6000,
+            expensive_per_api_key: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// API keys with the admin scope, which are never stored.
+    pub admin_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchlistConfig {
+    /// Default webhook URL that watchlist events are POSTed to.
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Which logs to print, as a `tracing` filter directive.
+    pub filter: String,
+    /// Whether to color the logs.
+    pub ansi: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "melscan=debug,warn,info".into(),
+            ansi: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the command line, the environment and the config file they point to, and checks it.
+    pub fn load() -> anyhow::Result<Self> {
+        let args = Args::from_args();
+        let mut config = match args.config.as_ref() {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("cannot read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides the settings that were given on the command line or in the environment.
+    fn apply(&mut self, args: Args) {
+        if !args.listen.is_empty() {
+            self.server.listen = args.listen;
+        }
+        if !args.cors_origin.is_empty() {
+            self.cors.public.origins = args.cors_origin;
+        }
+        if let Some(len) = args.min_prefix_len {
+            self.server.min_prefix_len = len;
+        }
+        if let Some(trust) = args.trust_forwarded_for {
+            self.server.trust_forwarded_for = trust.unwrap_or(true);
+        }
+        if let Some(secs) = args.request_timeout {
+            self.server.request_timeout = secs;
+        }
+        if let Some(secs) = args.expensive_request_timeout {
+            self.server.expensive_request_timeout = secs;
+        }
+
+        if args.connect.is_some() {
+            self.upstream.connect = args.connect;
+        }
+        if args.network.is_some() {
+            self.upstream.network = args.network;
+        }
+        if let Some(testnet) = args.testnet {
+            self.upstream.testnet = testnet.unwrap_or(true);
+        }
+        if let Some(concurrency) = args.upstream_concurrency {
+            self.upstream.concurrency = concurrency;
+        }
+
+        if args.blkidx_db.is_some() {
+            self.storage.blkidx_db = args.blkidx_db;
+        }
+        if args.local_db.is_some() {
+            self.storage.local_db = args.local_db;
+        }
+
+        if let Some(limit) = args.rate_limit {
+            self.rate_limits.per_ip = limit;
+        }
+        if let Some(limit) = args.expensive_rate_limit {
+            self.rate_limits.expensive_per_ip = limit;
+        }
+        if let Some(limit) = args.api_key_rate_limit {
+            self.rate_limits.per_api_key = limit;
+        }
+        if let Some(limit) = args.api_key_expensive_rate_limit {
+            self.rate_limits.expensive_per_api_key = limit;
+        }
+
+        // keys from the command line are added to the ones in the file
+        self.auth.admin_keys.extend(args.admin_key);
+        if args.webhook_url.is_some() {
+            self.watchlist.webhook_url = args.webhook_url;
+        }
+        if let Some(filter) = args.log {
+            self.log.filter = filter;
+        }
+        if let Some(ansi) = args.log_ansi {
+            self.log.ansi = ansi.unwrap_or(true);
+        }
+    }
+
+    /// Checks the configuration, listing everything wrong with it at once.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = vec![];
+        if self.server.listen.is_empty() {
+            problems.push("server.listen: no address to listen on (or pass --listen)".to_string());
+        }
+        if self.upstream.connect.is_none() {
+            problems
+                .push("upstream.connect: no full node to connect to (or pass --connect)".into());
+        }
+        if self.upstream.network.is_some() && self.upstream.testnet {
+            problems.push("upstream.network and upstream.testnet cannot both be set".into());
+        }
+        if self.upstream.concurrency == 0 {
+            problems.push("upstream.concurrency must be at least 1".into());
+        }
+        if !(MIN_PREFIX_LEN..=64).contains(&self.server.min_prefix_len) {
+            problems.push(format!(
+                "server.min_prefix_len must be between {} and 64",
+                MIN_PREFIX_LEN
+            ));
+        }
+        if self.server.request_timeout == 0 || self.server.expensive_request_timeout == 0 {
+            problems.push("server.request_timeout and server.expensive_request_timeout must be at least 1 second".into());
+        }
+        problems.extend(self.cors.problems());
+
+        let capacities = [
+            ("cache.address_capacity", self.cache.address_capacity),
+            (
+                "cache.leaderboard_capacity",
+                self.cache.leaderboard_capacity,
+            ),
+            ("cache.block_capacity", self.cache.block_capacity),
+            ("cache.crawl_capacity", self.cache.crawl_capacity),
+            ("cache.spend_capacity", self.cache.spend_capacity),
+            ("cache.address_ttl", self.cache.address_ttl),
+            ("cache.leaderboard_ttl", self.cache.leaderboard_ttl),
+        ];
+        for (name, value) in capacities.iter() {
+            if *value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+
+        for (name, path) in [
+            ("storage.blkidx_db", &self.storage.blkidx_db),
+            ("storage.local_db", &self.storage.local_db),
+        ]
+        .iter()
+        {
+            if let Some(parent) = path.as_ref().and_then(|p| p.parent()) {
+                if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                    problems.push(format!(
+                        "{}: directory {} does not exist",
+                        name,
+                        parent.display()
+                    ));
+                }
+            }
+        }
+        if self.auth.admin_keys.iter().any(|key| key.trim().is_empty()) {
+            problems.push("auth.admin_keys cannot contain empty keys".into());
+        }
+        // keys created through the admin API would silently disappear on the next restart
+        if !self.auth.admin_keys.is_empty() && self.storage.local_db.is_none() {
+            problems
+                .push("auth.admin_keys needs storage.local_db to store the keys it creates".into());
+        }
+        // watches live in the local database, so without one the default webhook would never be called
+        if self.watchlist.webhook_url.is_some() && self.storage.local_db.is_none() {
+            problems.push("watchlist.webhook_url needs storage.local_db to store watches".into());
+        }
+        if let Err(err) = EnvFilter::try_new(&self.log.filter) {
+            problems.push(format!("log.filter: {}", err));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("invalid configuration:\n  {}", problems.join("\n  "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_and_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            listen = ["127.0.0.1:13000"]
+
+            [upstream]
+            connect = "127.0.0.1:11814"
+            network = "testnet"
+
+            [cache]
+            address_ttl = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cache.address_ttl, 60);
+        assert_eq!(config.cache.address_capacity, 10000);
+        assert_eq!(config.upstream.netid(), NetID::Testnet);
+        config.validate().unwrap();
+
+        config.apply(Args {
+            listen: vec!["0.0.0.0:8080".parse().unwrap()],
+            rate_limit: Some(0),
+            trust_forwarded_for: Some(None),
+            ..Default::default()
+        });
+        assert_eq!(config.server.listen, vec!["0.0.0.0:8080".parse().unwrap()]);
+        assert_eq!(config.rate_limits.per_ip, 0);
+        assert!(config.server.trust_forwarded_for);
+
+        // a flag that is on in the file can be turned off
+        config.apply(Args {
+            trust_forwarded_for: Some(Some(false)),
+            ..Default::default()
+        });
+        assert!(!config.server.trust_forwarded_for);
+        assert_eq!(config.server.listen, vec!["0.0.0.0:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn lists_every_problem() {
+        let mut config = Config::default();
+        config.cache.block_capacity = 0;
+        config.cors.admin.origins = vec!["example.org".into()];
+        config.server.min_prefix_len = 1;
+        config.auth.admin_keys = vec!["secret".into()];
+        config.watchlist.webhook_url = Some("http://127.0.0.1:8000/hook".into());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("server.listen"));
+        assert!(err.contains("upstream.connect"));
+        assert!(err.contains("cache.block_capacity"));
+        assert!(err.contains("cors.admin.origins"));
+        assert!(err.contains("server.min_prefix_len"));
+        assert!(err.contains("auth.admin_keys needs storage.local_db"));
+        assert!(err.contains("watchlist.webhook_url needs storage.local_db"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use warp::hyper::{
+    header::{HeaderName, HeaderValue},
+    Method,
+};
+
+use crate::export::is_export;
+
+/// Groups of routes that each get their own CORS policy. The order is that of the services in `main`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorsGroup {
+    /// The public API under `/raw`, and GraphQL.
+    Public,
+    /// The admin API under `/raw/admin`.
+    Admin,
+    /// Streamed CSV and NDJSON exports.
+    Stream,
+}
+
+impl CorsGroup {
+    /// The group that a request belongs to. Preflight requests carry the same path and query as the request they are for.
+    pub fn of(path: &str, query: Option<&str>) -> Self {
+        if path.starts_with("/raw/admin/") {
+            CorsGroup::Admin
+        } else if is_export(query.unwrap_or_default()) {
+            CorsGroup::Stream
+        } else {
+            CorsGroup::Public
+        }
+    }
+}
+
+/// The CORS policy for every route group.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub public: CorsPolicy,
+    pub admin: CorsPolicy,
+    pub stream: CorsPolicy,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            public: CorsPolicy::any_origin(),
+            // browsers can only reach the admin API from origins that are listed explicitly
+            admin: CorsPolicy::default(),
+            stream: CorsPolicy::any_origin(),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The policy for a group.
+    pub fn policy(&self, group: CorsGroup) -> &CorsPolicy {
+        match group {
+            CorsGroup::Public => &self.public,
+            CorsGroup::Admin => &self.admin,
+            CorsGroup::Stream => &self.stream,
+        }
+    }
+
+    /// Describes everything wrong with the policies.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.public.problems("cors.public");
+        problems.extend(self.admin.problems("cors.admin"));
+        problems.extend(self.stream.problems("cors.stream"));
+        problems
+    }
+}
+
+/// Which browser origins can call a group of routes, and how.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsPolicy {
+    /// Allowed origins, like `https://scan.themelio.org`. `*` allows any origin, and no origins blocks cross-origin calls.
+    pub origins: Vec<String>,
+    /// Request headers that browsers may send.
+    pub headers: Vec<String>,
+    /// Whether browsers may send cookies and other credentials.
+    pub credentials: bool,
+    /// Seconds that browsers may cache a preflight response for.
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            origins: vec![],
+            headers: vec![
+                "content-type".into(),
+                "x-api-key".into(),
+                "if-none-match".into(),
+            ],
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsPolicy {
+    fn any_origin() -> Self {
+        Self {
+            origins: vec!["*".into()],
+            ..Default::default()
+        }
+    }
+
+    /// Builds the warp filter for the policy. The policy must have been checked for problems first, since warp panics on invalid origins and headers.
+    pub fn build(&self) -> warp::cors::Builder {
+        let cors = if self.origins.iter().any(|origin| origin == "*") {
+            warp::cors().allow_any_origin()
+        } else {
+            warp::cors().allow_origins(self.origins.iter().map(|origin| origin.as_str()))
+        };
+        let cors = cors
+            .allow_methods(vec![
+                Method::GET,
+                Method::POST,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_headers(self.headers.iter().map(|header| header.as_str()))
+            .allow_credentials(self.credentials)
+            .expose_headers(vec!["etag", "retry-after"]);
+        match self.max_age {
+            Some(secs) => cors.max_age(Duration::from_secs(secs)),
+            None => cors,
+        }
+    }
+
+    fn problems(&self, name: &str) -> Vec<String> {
+        let mut problems = vec![];
+        for origin in self.origins.iter() {
+            if let Err(err) = check_origin(origin) {
+                problems.push(format!("{}.origins: {:?} {}", name, origin, err));
+            }
+        }
+        let any_origin = self.origins.iter().any(|origin| origin == "*");
+        if any_origin && self.origins.len() > 1 {
+            problems.push(format!(
+                "{}.origins: \"*\" cannot be combined with other origins",
+                name
+            ));
+        }
+        if any_origin && self.credentials {
+            problems.push(format!(
+                "{}.credentials: cannot be allowed for any origin",
+                name
+            ));
+        }
+        for header in self.headers.iter() {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                problems.push(format!(
+                    "{}.headers: {:?} is not a header name",
+                    name, header
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// Checks that a CORS origin is either `*` or a bare `scheme://host[:port]`.
+fn check_origin(origin: &str) -> Result<(), &'static str> {
+    if origin == "*" {
+        return Ok(());
+    }
+    let (scheme, host) = origin
+        .split_once("://")
+        .ok_or("is missing a scheme, as in https://example.org")?;
+    if scheme != "http" && scheme != "https" {
+        return Err("must use http or https");
+    }
+    if host.is_empty() || host.contains('/') {
+        return Err("must be only a scheme and host, without a path");
+    }
+    // browsers send origins back byte for byte, so only a well-formed one can ever match
+    if HeaderValue::from_str(origin).is_err() {
+        return Err("is not a valid header value");
+    }
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (host, None),
+    };
+    if let Some(port) = port {
+        if port.parse::<u16>().is_err() {
+            return Err("has an invalid port");
+        }
+    }
+    let valid_host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6
+            .strip_suffix(']')
+            .map(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok())
+            .unwrap_or(false),
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        }
+    };
+    if !valid_host {
+        return Err("has an invalid host");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups() {
+        assert_eq!(CorsGroup::of("/raw/admin/keys", None), CorsGroup::Admin);
+        assert_eq!(
+            CorsGroup::of("/raw/leaderboard/MEL", Some("format=csv")),
+            CorsGroup::Stream
+        );
+        assert_eq!(
+            CorsGroup::of("/raw/leaderboard/MEL", Some("format=%6Edjson")),
+            CorsGroup::Stream
+        );
+        assert_eq!(CorsGroup::of("/raw/latest", None), CorsGroup::Public);
+    }
+
+    #[test]
+    fn policy_problems() {
+        let policy = CorsPolicy {
+            origins: vec!["*".into()],
+            headers: vec!["x api key".into()],
+            credentials: true,
+            max_age: None,
+        };
+        assert_eq!(policy.problems("cors.public").len(), 2);
+        assert!(CorsConfig::default().problems().is_empty());
+    }
+
+    #[test]
+    fn origins() {
+        for origin in [
+            "*",
+            "https://example.org",
+            "http://localhost:5173",
+            "http://[::1]:8080",
+        ] {
+            assert_eq!(check_origin(origin), Ok(()), "{}", origin);
+        }
+        for origin in [
+            "example.org",
+            "ftp://example.org",
+            "https://example.org/",
+            "https://example.org:abc",
+            "https://example.org:99999",
+            "https://exa mple.org",
+            "https://example.org:",
+            "https://[::1",
+            "https://",
+        ] {
+            assert!(check_origin(origin).is_err(), "{}", origin);
+        }
+    }
+}
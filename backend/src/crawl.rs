@@ -7,7 +7,18 @@ use moka::sync::Cache;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use crate::globals::{BACKEND, CLIENT};
+use crate::{
+    globals::{BACKEND, CLIENT, CONFIG},
+    upstream::upstream,
+};
+
+/// Finished crawls, by the transaction they surround.
+pub static CRAWL_CACHE: Lazy<Cache<TxHash, CoinCrawl>> =
+    Lazy::new(|| Cache::new(CONFIG.cache.crawl_capacity));
+
+/// Where and by which transaction coins were spent.
+pub static SPEND_CACHE: Lazy<Cache<CoinID, (BlockHeight, TxHash)>> =
+    Lazy::new(|| Cache::new(CONFIG.cache.spend_capacity));
 
 /// A "crawl" of coin activity around a particular transaction. Coins are represented as string CoinIDs.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -26,8 +37,7 @@ pub struct CrawlItem {
 impl CoinCrawl {
     /// Create a coin crawl surrounding the given TxHash and height.
     pub async fn crawl(height: BlockHeight, txhash: TxHash) -> anyhow::Result<Self> {
-        static CACHE: Lazy<Cache<TxHash, CoinCrawl>> = Lazy::new(|| Cache::new(10000));
-        if let Some(res) = CACHE.get(&txhash) {
+        if let Some(res) = CRAWL_CACHE.get(&txhash) {
             Ok(res)
         } else {
             let snap = CLIENT.snapshot(height).await?;
@@ -40,7 +50,9 @@ impl CoinCrawl {
             let input_crawls = join_all(transaction.inputs.clone().into_iter().map(|coinid| {
                 let coindata_fut = snap.get_coin_spent_here(coinid);
                 async move {
-                    let coindata = coindata_fut.await?.context("must be spent here")?;
+                    let coindata = upstream(coindata_fut)
+                        .await?
+                        .context("must be spent here")?;
                     // also get the content
                     anyhow::Ok(CrawlItem {
                         coinid,
@@ -62,7 +74,7 @@ impl CoinCrawl {
                 let coindata = transaction.outputs[i].clone();
                 let spender_fut = find_spend_within_range(coinid, height_range.clone());
                 async move {
-                    let spender = spender_fut.await?;
+                    let spender = upstream(spender_fut).await?;
                     anyhow::Ok(CrawlItem {
                         coinid,
                         coindata,
@@ -81,7 +93,7 @@ impl CoinCrawl {
             };
             // ONLY cache if all the coins are spent. This prevents us from caching stale things
             if crawls.crawls.iter().all(|c| c.spender.is_some()) {
-                CACHE.insert(txhash, crawls.clone());
+                CRAWL_CACHE.insert(txhash, crawls.clone());
             }
             Ok(crawls)
         }
@@ -92,9 +104,7 @@ async fn find_spend_within_range(
     coinid: CoinID,
     height_range: Range<u64>,
 ) -> anyhow::Result<Option<(BlockHeight, TxHash)>> {
-    static CACHE: Lazy<Cache<CoinID, (BlockHeight, TxHash)>> = Lazy::new(|| Cache::new(100000));
-
-    if let Some(existant) = CACHE.get(&coinid) {
+    if let Some(existant) = SPEND_CACHE.get(&coinid) {
         Ok(Some(existant))
     } else {
         let range = height_range;
@@ -110,7 +120,7 @@ async fn find_spend_within_range(
         let spend_txhash = spend_tx
             .context("Unexpected Failure: couldn't find spending transaction in spending block")?
             .hash_nosigs();
-        CACHE.insert(coinid, (spend_height, spend_txhash));
+        SPEND_CACHE.insert(coinid, (spend_height, spend_txhash));
         Ok(Some((spend_height, spend_txhash)))
     }
 }
@@ -0,0 +1,289 @@
+use std::{collections::BTreeSet, io::Cursor, ops::Range};
+
+use melstructs::Address;
+use melvm::{covenant_weight_from_bytes, opcode::OpCode};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+
+/// An immediate argument of a MelVM instruction.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum OpArg {
+    /// A 16-bit immediate, such as a heap address, jump gap or loop count.
+    U16(u16),
+    /// A 256-bit integer, in decimal.
+    U256(String),
+    /// A byte string, in hex.
+    Bytes(String),
+}
+
+/// A single decoded MelVM instruction.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Op {
+    /// Position of the instruction in the covenant.
+    pub index: usize,
+    /// Byte offset of the instruction in the covenant.
+    pub offset: usize,
+    /// Name of the opcode, like `PushB` or `LoadImm`.
+    pub name: String,
+    pub args: Vec<OpArg>,
+}
+
+impl Op {
+    fn new(index: usize, offset: usize, opcode: &OpCode) -> Self {
+        let (name, args) = match opcode {
+            OpCode::Hash(n) => ("Hash".into(), vec![OpArg::U16(*n)]),
+            OpCode::SigEOk(n) => ("SigEOk".into(), vec![OpArg::U16(*n)]),
+            OpCode::LoadImm(n) => ("LoadImm".into(), vec![OpArg::U16(*n)]),
+            OpCode::StoreImm(n) => ("StoreImm".into(), vec![OpArg::U16(*n)]),
+            OpCode::Bez(n) => ("Bez".into(), vec![OpArg::U16(*n)]),
+            OpCode::Bnz(n) => ("Bnz".into(), vec![OpArg::U16(*n)]),
+            OpCode::Jmp(n) => ("Jmp".into(), vec![OpArg::U16(*n)]),
+            OpCode::Loop(iterations, count) => (
+                "Loop".into(),
+                vec![OpArg::U16(*iterations), OpArg::U16(*count)],
+            ),
+            OpCode::PushB(bytes) => ("PushB".into(), vec![OpArg::Bytes(hex::encode(bytes))]),
+            OpCode::PushI(num) => ("PushI".into(), vec![OpArg::U256(num.to_string())]),
+            OpCode::PushIC(num) => ("PushIC".into(), vec![OpArg::U256(num.to_string())]),
+            // every other opcode has no immediates, so its Debug form is exactly its name
+            other => (format!("{:?}", other), vec![]),
+        };
+        Self {
+            index,
+            offset,
+            name,
+            args,
+        }
+    }
+
+    /// The 16-bit immediates of this instruction, if that is all it has.
+    fn u16_args(&self) -> Option<Vec<u16>> {
+        self.args
+            .iter()
+            .map(|arg| match arg {
+                OpArg::U16(n) => Some(*n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Where execution continues if this instruction jumps, if it is a jump at all.
+    fn jump_target(&self) -> Option<usize> {
+        match (self.name.as_str(), self.u16_args()?.as_slice()) {
+            ("Jmp" | "Bez" | "Bnz", [gap]) => Some(self.index + 1 + *gap as usize),
+            _ => None,
+        }
+    }
+
+    /// Renders the instruction in lowercase MIL-like syntax, like `loadimm 1`.
+    fn render(&self, ops_len: usize) -> String {
+        let mut out = self.name.to_lowercase();
+        if let Some(target) = self.jump_target() {
+            out.push(' ');
+            out.push_str(&label(target, ops_len));
+            return out;
+        }
+        for arg in self.args.iter() {
+            out.push(' ');
+            match arg {
+                OpArg::U16(n) => out.push_str(&n.to_string()),
+                OpArg::U256(n) => out.push_str(n),
+                OpArg::Bytes(b) => {
+                    out.push_str("0x");
+                    out.push_str(b)
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A node in the control-flow rendering of a covenant. Loop bodies are nested, and jumps point at the index of the instruction where execution continues.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FlowNode {
+    Op {
+        op: Op,
+    },
+    Loop {
+        op: Op,
+        iterations: u16,
+        body: Vec<FlowNode>,
+    },
+    Jump {
+        op: Op,
+        target: usize,
+    },
+}
+
+/// The full disassembly of a covenant.
+#[serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct Disassembly {
+    #[serde_as(as = "DisplayFromStr")]
+    pub address: Address,
+    pub size: usize,
+    pub weight: u128,
+    pub ops: Vec<Op>,
+    pub flow: Vec<FlowNode>,
+    /// Human-readable listing, one instruction per line.
+    pub pretty: Vec<String>,
+}
+
+/// Decodes every instruction of a covenant.
+pub fn decode_ops(covenant: &[u8]) -> anyhow::Result<Vec<OpCode>> {
+    Ok(decode_with_offsets(covenant)?
+        .into_iter()
+        .map(|(_, opcode)| opcode)
+        .collect())
+}
+
+/// Decodes every instruction of a covenant, along with its byte offset.
+fn decode_with_offsets(covenant: &[u8]) -> anyhow::Result<Vec<(usize, OpCode)>> {
+    let mut cursor = Cursor::new(covenant);
+    let mut ops = vec![];
+    while (cursor.position() as usize) < covenant.len() {
+        let offset = cursor.position() as usize;
+        ops.push((offset, OpCode::decode(&mut cursor)?));
+    }
+    Ok(ops)
+}
+
+/// Disassembles arbitrary covenant bytes.
+pub fn disassemble(covenant: &[u8]) -> anyhow::Result<Disassembly> {
+    let ops: Vec<Op> = decode_with_offsets(covenant)?
+        .iter()
+        .enumerate()
+        .map(|(index, (offset, opcode))| Op::new(index, *offset, opcode))
+        .collect();
+    let flow = build_flow(&ops, 0..ops.len());
+    let pretty = pretty_print(&ops, &flow);
+    Ok(Disassembly {
+        address: Address(tmelcrypt::hash_single(covenant)),
+        size: covenant.len(),
+        weight: covenant_weight_from_bytes(covenant),
+        ops,
+        flow,
+        pretty,
+    })
+}
+
+fn build_flow(ops: &[Op], range: Range<usize>) -> Vec<FlowNode> {
+    let mut nodes = vec![];
+    let mut i = range.start;
+    while i < range.end {
+        let op = ops[i].clone();
+        let loop_args = if op.name == "Loop" {
+            op.u16_args()
+        } else {
+            None
+        };
+        if let Some([iterations, count]) = loop_args.as_deref() {
+            let body_end = (i + 1 + *count as usize).min(range.end);
+            nodes.push(FlowNode::Loop {
+                iterations: *iterations,
+                body: build_flow(ops, i + 1..body_end),
+                op,
+            });
+            i = body_end;
+        } else if let Some(target) = op.jump_target() {
+            nodes.push(FlowNode::Jump { op, target });
+            i += 1;
+        } else {
+            nodes.push(FlowNode::Op { op });
+            i += 1;
+        }
+    }
+    nodes
+}
+
+fn pretty_print(ops: &[Op], flow: &[FlowNode]) -> Vec<String> {
+    let targets: BTreeSet<usize> = ops.iter().filter_map(|op| op.jump_target()).collect();
+    let mut lines = vec![];
+    pretty_print_inner(flow, 0, ops.len(), &targets, &mut lines);
+    if targets.iter().any(|t| *t >= ops.len()) {
+        lines.push(format!("{}:", label(ops.len(), ops.len())));
+    }
+    lines
+}
+
+fn pretty_print_inner(
+    nodes: &[FlowNode],
+    depth: usize,
+    ops_len: usize,
+    targets: &BTreeSet<usize>,
+    lines: &mut Vec<String>,
+) {
+    let indent = "  ".repeat(depth);
+    for node in nodes {
+        let op = match node {
+            FlowNode::Op { op } | FlowNode::Loop { op, .. } | FlowNode::Jump { op, .. } => op,
+        };
+        if targets.contains(&op.index) {
+            lines.push(format!("{}:", label(op.index, ops_len)));
+        }
+        lines.push(format!(
+            "{:04x}  {}{}",
+            op.offset,
+            indent,
+            op.render(ops_len)
+        ));
+        if let FlowNode::Loop { body, .. } = node {
+            pretty_print_inner(body, depth + 1, ops_len, targets, lines);
+            lines.push(format!("      {}end", indent));
+        }
+    }
+}
+
+fn label(target: usize, ops_len: usize) -> String {
+    if target >= ops_len {
+        "L_end".into()
+    } else {
+        format!("L{}", target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(index: usize, name: &str, args: Vec<OpArg>) -> Op {
+        Op {
+            index,
+            offset: index,
+            name: name.into(),
+            args,
+        }
+    }
+
+    #[test]
+    fn flow_nests_loops_and_resolves_jumps() {
+        let ops = vec![
+            op(0, "Loop", vec![OpArg::U16(3), OpArg::U16(2)]),
+            op(1, "Dup", vec![]),
+            op(2, "Add", vec![]),
+            op(3, "Bez", vec![OpArg::U16(1)]),
+            op(4, "Noop", vec![]),
+            op(5, "Noop", vec![]),
+        ];
+        let flow = build_flow(&ops, 0..ops.len());
+        assert_eq!(flow.len(), 4);
+        match &flow[0] {
+            FlowNode::Loop {
+                iterations, body, ..
+            } => {
+                assert_eq!(*iterations, 3);
+                assert_eq!(body.len(), 2);
+            }
+            other => panic!("expected a loop, got {:?}", other),
+        }
+        match &flow[1] {
+            FlowNode::Jump { target, .. } => assert_eq!(*target, 5),
+            other => panic!("expected a jump, got {:?}", other),
+        }
+        let pretty = pretty_print(&ops, &flow);
+        assert_eq!(pretty[0], "0000  loop 3 2");
+        assert!(pretty.contains(&"L5:".to_string()));
+    }
+}
@@ -5,9 +5,8 @@ use std::collections::HashMap;
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::{Infallible, TryInto};
 use std::fmt::Display;
-use std::io::Cursor;
 
-use futures_util::Future;
+use futures_util::{stream, Future, StreamExt, TryStreamExt};
 use num_traits::ToPrimitive;
 use once_cell::sync::Lazy;
 use rweb::*;
@@ -17,16 +16,33 @@ use serde::{ser::SerializeTupleStruct, Deserialize};
 use melstructs::*;
 use melvm::covenant_weight_from_bytes;
 use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
 use smol::Task;
-use tmelcrypt::{HashVal, Hashable};
+use tmelcrypt::HashVal;
 use tracing::{debug, info};
 
 use crate::{
-    crawl::CoinCrawl,
-    globals::{BACKEND, CLIENT},
-    graphs::{datetime_to_height, graph_range},
+    apikeys::{Scope, Unauthorized},
+    backend::CoinStatus,
+    batch::{run_batch, BatchQuery, MAX_BATCH_BODY_BYTES, MAX_BATCH_SIZE},
+    chainidx::TxFilter,
+    classify::{classify, CovenantClass},
+    crawl::{CoinCrawl, CRAWL_CACHE, SPEND_CACHE},
+    disasm::{disassemble, Disassembly},
+    export::{
+        blocking_rows, export_response, AddressHistoryRow, ExportFormat, ExportQuery, HolderRow,
+        TxRow,
+    },
+    globals::{API_KEYS, BACKEND, CLIENT, CONFIG, RATE_LIMITER, WATCHLIST},
+    graphql::{MAX_QUERY_BYTES, SCHEMA},
+    graphs::{datetime_to_height, graph_range, GraphDatum, GraphPoint},
+    ratelimit::{client, ClientId, RateLimited, RouteClass},
+    search::{resolve_block_prefix, resolve_transaction_prefix, search, PrefixResolution},
+    trace::trace_transaction,
+    upstream::upstream,
+    utils::BLOCK_MEMORY_CACHE,
+    watchlist::{WatchRule, Watchlist},
 };
-use melvm::opcode;
 
 type DynReply = Result<Box<dyn warp::Reply>, Infallible>;
 
@@ -80,39 +96,422 @@ async fn generic_fallible_json_option<R: Serialize>(
     .await
 }
 
-#[get("/raw/overview")]
-pub async fn overview() -> DynReply {
-    generic_fallible_json(async move {
-        let overview = BACKEND.get_overview(None).await?;
-        let height = overview.recent_blocks[0].header.height;
-        let mut o = overview.clone();
-        o.recent_blocks = vec![];
-        let overview2 = serde_json::to_string_pretty(&o)?;
-        debug!("Found Height: {height}");
-        println!("{overview2:?}");
-        anyhow::Ok(overview)
+/// How long a response may be cached for.
+#[derive(Clone, Copy, Debug)]
+enum Freshness {
+    /// Data about a confirmed block, which never changes.
+    Immutable,
+    /// Data that depends on the tip, which changes every block.
+    MaxAge(u64),
+}
+
+/// Extracts the `If-None-Match` header, if any.
+fn if_none_match() -> impl warp::Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone
+{
+    warp::header::optional("if-none-match")
+}
+
+/// How a response body can be encoded.
+#[derive(Clone, Debug)]
+enum BodyFormat {
+    Json,
+    /// JSON, or the canonical stdcode encoding if the client's `Accept` header asks for `application/octet-stream`. Holds that header.
+    Negotiated(Option<String>),
+}
+
+impl BodyFormat {
+    fn wants_stdcode(&self) -> bool {
+        match self {
+            BodyFormat::Json => false,
+            BodyFormat::Negotiated(accept) => {
+                accept
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .any(|media| {
+                        media.split(';').next().unwrap_or_default().trim()
+                            == "application/octet-stream"
+                    })
+            }
+        }
+    }
+}
+
+/// Extracts the `Accept` header, if any.
+fn accept() -> impl warp::Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional("accept")
+}
+
+/// Serializes data with `Cache-Control` and a content-hash `ETag`, or returns a `304 Not Modified` if the client already has it.
+fn cached_reply<R: Serialize>(
+    data: &R,
+    format: &BodyFormat,
+    freshness: Freshness,
+    if_none_match: Option<&str>,
+) -> anyhow::Result<Box<dyn warp::Reply>> {
+    use rweb::hyper::{header, Body, Response, StatusCode};
+    let (body, content_type) = if format.wants_stdcode() {
+        (stdcode::serialize(data)?, "application/octet-stream")
+    } else {
+        (serde_json::to_vec(data)?, "application/json")
+    };
+    let etag = format!("\"{}\"", tmelcrypt::hash_single(&body));
+    let cache_control = match freshness {
+        Freshness::Immutable => "public, max-age=31536000, immutable".to_string(),
+        Freshness::MaxAge(secs) => format!("public, max-age={}", secs),
+    };
+    let not_modified = if_none_match
+        .map(|inm| {
+            inm.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        })
+        .unwrap_or(false);
+    let mut response = Response::builder()
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    if let BodyFormat::Negotiated(_) = format {
+        response = response.header(header::VARY, "accept");
+    }
+    let response = if not_modified {
+        response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?
+    } else {
+        response
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))?
+    };
+    Ok(Box::new(response))
+}
+
+/// Helper function for data about confirmed blocks, which is cacheable forever once it exists. Returns a JSON `null`, uncached, for None, or a 404 if stdcode was asked for.
+async fn generic_immutable<R: Serialize>(
+    data: impl Future<Output = anyhow::Result<Option<R>>>,
+    format: BodyFormat,
+    if_none_match: Option<String>,
+) -> DynReply {
+    generic_fallible(async {
+        let r: Box<dyn warp::Reply> = match data.await? {
+            Some(data) => cached_reply(
+                &data,
+                &format,
+                Freshness::Immutable,
+                if_none_match.as_deref(),
+            )?,
+            None if format.wants_stdcode() => Box::new(rweb::reply::with_status(
+                rweb::reply::reply(),
+                rweb::hyper::StatusCode::NOT_FOUND,
+            )),
+            None => Box::new(rweb::reply::json(&serde_json::Value::Null)),
+        };
+        Ok(r)
+    })
+    .await
+}
+
+/// Like `generic_immutable`, but always returns a 404 for None.
+async fn generic_immutable_option<R: Serialize>(
+    data: impl Future<Output = anyhow::Result<Option<R>>>,
+    format: BodyFormat,
+    if_none_match: Option<String>,
+) -> DynReply {
+    generic_fallible(async {
+        let r: Box<dyn warp::Reply> = match data.await? {
+            Some(data) => cached_reply(
+                &data,
+                &format,
+                Freshness::Immutable,
+                if_none_match.as_deref(),
+            )?,
+            None => Box::new(rweb::reply::with_status(
+                rweb::reply::reply(),
+                rweb::hyper::StatusCode::NOT_FOUND,
+            )),
+        };
+        Ok(r)
+    })
+    .await
+}
+
+/// Helper function for JSON that changes with every block, and so is only cached briefly.
+async fn generic_fresh_json<R: Serialize>(
+    data: impl Future<Output = anyhow::Result<R>>,
+    if_none_match: Option<String>,
+) -> DynReply {
+    generic_fallible(async {
+        cached_reply(
+            &data.await?,
+            &BodyFormat::Json,
+            Freshness::MaxAge(TIP_MAX_AGE_SECS),
+            if_none_match.as_deref(),
+        )
     })
     .await
 }
 
+/// How long responses that depend on the tip can be cached for.
+const TIP_MAX_AGE_SECS: u64 = 10;
+
+/// Turns rate limit and authorization rejections into error responses, passing on every other rejection.
+pub async fn handle_rejection(
+    rejection: warp::Rejection,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(limited) = rejection.find::<RateLimited>() {
+        return Ok(rate_limited(limited.retry_after));
+    }
+    if let Some(unauthorized) = rejection.find::<Unauthorized>() {
+        let status = if unauthorized.authenticated {
+            rweb::hyper::StatusCode::FORBIDDEN
+        } else {
+            rweb::hyper::StatusCode::UNAUTHORIZED
+        };
+        return Ok(Box::new(rweb::reply::with_status(
+            rweb::reply::json(&serde_json::json!({
+                "error": format!("needs an API key with the {} scope", unauthorized.scope.as_str())
+            })),
+            status,
+        )));
+    }
+    Err(rejection)
+}
+
+fn rate_limited(retry_after: u64) -> Box<dyn warp::Reply> {
+    let reply = rweb::reply::with_status(
+        rweb::reply::json(&serde_json::json!({ "error": "rate limit exceeded" })),
+        rweb::hyper::StatusCode::TOO_MANY_REQUESTS,
+    );
+    Box::new(rweb::reply::with_header(
+        reply,
+        rweb::hyper::header::RETRY_AFTER,
+        retry_after.to_string(),
+    ))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct OverviewQuery {
+    blocks: Option<usize>,
+    transactions: Option<bool>,
+}
+
+#[get("/raw/overview")]
+pub async fn overview(
+    qs: Query<OverviewQuery>,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fresh_json(
+        BACKEND.get_overview(
+            None,
+            qs.blocks.unwrap_or(50).min(200),
+            qs.transactions.unwrap_or(true),
+        ),
+        inm,
+    )
+    .await
+}
+
 #[get("/raw/latest")]
-pub async fn latest() -> DynReply {
-    generic_fallible_json(BACKEND.get_latest_header()).await
+pub async fn latest(#[filter = "if_none_match"] inm: Option<String>) -> DynReply {
+    generic_fresh_json(BACKEND.get_latest_header(), inm).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+#[get("/raw/search")]
+pub async fn search_any(qs: Query<SearchQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json(search(&qs.q)).await
+}
+
+async fn prefix_reply(
+    resolution: impl Future<Output = anyhow::Result<PrefixResolution>>,
+) -> DynReply {
+    generic_fallible(async {
+        let r: Box<dyn warp::Reply> = match resolution.await? {
+            PrefixResolution::Unique(found) => Box::new(rweb::reply::json(&found)),
+            PrefixResolution::NotFound => Box::new(rweb::reply::with_status(
+                rweb::reply::reply(),
+                rweb::hyper::StatusCode::NOT_FOUND,
+            )),
+            PrefixResolution::Invalid => Box::new(rweb::reply::with_status(
+                rweb::reply::json(&serde_json::json!({
+                    "error": format!(
+                        "a prefix must be between {} and 64 hex digits",
+                        CONFIG.server.min_prefix_len
+                    )
+                })),
+                rweb::hyper::StatusCode::BAD_REQUEST,
+            )),
+            PrefixResolution::Ambiguous(matches) => Box::new(rweb::reply::with_status(
+                rweb::reply::json(&serde_json::json!({
+                    "error": "ambiguous prefix",
+                    "matches": matches
+                })),
+                rweb::hyper::StatusCode::CONFLICT,
+            )),
+        };
+        Ok(r)
+    })
+    .await
+}
+
+#[get("/raw/resolve/transaction/{prefix}")]
+pub async fn resolve_transaction(prefix: String) -> DynReply {
+    prefix_reply(resolve_transaction_prefix(&prefix)).await
+}
+
+#[get("/raw/resolve/block/{prefix}")]
+pub async fn resolve_block(prefix: String) -> DynReply {
+    prefix_reply(resolve_block_prefix(&prefix)).await
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+struct TxSearchQuery {
+    from_height: Option<BlockHeight>,
+    to_height: Option<BlockHeight>,
+    from_date: Option<chrono::DateTime<Utc>>,
+    to_date: Option<chrono::DateTime<Utc>>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    kind: Option<TxKind>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    denom: Option<Denom>,
+    min_value: Option<u64>,
+    max_value: Option<u64>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    address: Option<Address>,
+    has_covenant: Option<bool>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+    format: Option<ExportFormat>,
+}
+
+/// At most how many transactions a single export returns.
+const MAX_EXPORTED_TRANSACTIONS: usize = 100_000;
+
+#[get("/raw/search/transactions")]
+pub async fn search_transactions(qs: Query<TxSearchQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    let format = qs.format.unwrap_or(ExportFormat::Json);
+    let limit = qs.limit;
+    let filter = async move {
+        let mut start = qs.from_height.unwrap_or_default();
+        if let Some(date) = qs.from_date {
+            start = start.max(datetime_to_height(date));
+        }
+        let mut end = qs.to_height.unwrap_or(BlockHeight(u64::MAX));
+        if let Some(date) = qs.to_date {
+            end = end.min(datetime_to_height(date));
+        }
+        let has_value_filter = qs.min_value.is_some() || qs.max_value.is_some();
+        let after = match qs.cursor {
+            Some(cursor) => {
+                let (height, txhash) = cursor.split_once(':').context("malformed cursor")?;
+                Some((height.parse()?, txhash.parse()?))
+            }
+            None => None,
+        };
+        anyhow::Ok(TxFilter {
+            start,
+            end,
+            kind: qs.kind,
+            // value bounds are in MEL unless said otherwise
+            denom: qs.denom.or_else(|| has_value_filter.then_some(Denom::Mel)),
+            min_value: qs.min_value.map(|v| v as u128),
+            max_value: qs.max_value.map(|v| v as u128),
+            address: qs.address,
+            has_covenant: qs.has_covenant,
+            after,
+            limit: qs.limit.unwrap_or(50).clamp(1, 500),
+        })
+    };
+    if format == ExportFormat::Json {
+        return generic_fallible_json(
+            async move { BACKEND.search_transactions(filter.await?).await },
+        )
+        .await;
+    }
+    generic_fallible(async move {
+        let mut filter = filter.await?;
+        // when exporting, the limit caps the whole export and pages are fetched as the client reads
+        let total = limit
+            .unwrap_or(MAX_EXPORTED_TRANSACTIONS)
+            .clamp(1, MAX_EXPORTED_TRANSACTIONS);
+        filter.limit = 500;
+        let pages = stream::try_unfold(Some((filter, total)), |state| async move {
+            let (mut filter, remaining) = match state {
+                Some(state) if state.1 > 0 => state,
+                _ => return anyhow::Ok(None),
+            };
+            filter.limit = filter.limit.min(remaining);
+            let page = BACKEND.search_transactions(filter).await?;
+            let next = match (page.transactions.last(), &page.next) {
+                (Some(last), Some(_)) => {
+                    filter.after = Some((last.height, last.txhash));
+                    Some((filter, remaining - page.transactions.len()))
+                }
+                _ => None,
+            };
+            Ok(Some((page.transactions, next)))
+        });
+        let rows = pages
+            .map_ok(|page| stream::iter(page.into_iter().map(|tx| anyhow::Ok(TxRow::from(tx)))))
+            .try_flatten();
+        export_response(format, rows)
+    })
+    .await
 }
 
 #[get("/raw/search/transaction/{txhash}")]
-pub async fn search_transaction(txhash: TxHash) -> DynReply {
-    generic_fallible_json_option(BACKEND.search_transaction(txhash)).await
+pub async fn search_transaction(
+    txhash: TxHash,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable_option(BACKEND.search_transaction(txhash), BodyFormat::Json, inm).await
 }
 
 #[get("/raw/search/block/{blkhash}")]
-pub async fn search_block(blkhash: HashVal) -> DynReply {
-    generic_fallible_json_option(BACKEND.search_block(blkhash)).await
+pub async fn search_block(
+    blkhash: HashVal,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable_option(BACKEND.search_block(blkhash), BodyFormat::Json, inm).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BlocksQuery {
+    from: Option<BlockHeight>,
+    to: Option<BlockHeight>,
+    limit: Option<usize>,
+}
+
+#[get("/raw/blocks")]
+pub async fn blocks(qs: Query<BlocksQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json(BACKEND.get_blocks(qs.from, qs.to, qs.limit.unwrap_or(50).clamp(1, 200)))
+        .await
 }
 
 #[get("/raw/blocks/{height}/transactions/{txhash}")]
-pub async fn transaction(height: BlockHeight, txhash: TxHash) -> DynReply {
-    generic_fallible_json_option(BACKEND.get_transaction_at_height(height, txhash)).await
+pub async fn transaction(
+    height: BlockHeight,
+    txhash: TxHash,
+    #[filter = "accept"] accept: Option<String>,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable_option(
+        BACKEND.get_transaction_at_height(height, txhash),
+        BodyFormat::Negotiated(accept),
+        inm,
+    )
+    .await
 }
 
 #[get("/raw/blocks/{height}/transactions/{txhash}/crawl")]
@@ -120,31 +519,371 @@ pub async fn transaction_crawl(height: BlockHeight, txhash: TxHash) -> DynReply
     generic_fallible_json(CoinCrawl::crawl(height, txhash)).await
 }
 
+#[get("/raw/blocks/{height}/transactions/{txhash}/trace")]
+pub async fn transaction_trace(
+    height: BlockHeight,
+    txhash: TxHash,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable_option(trace_transaction(height, txhash), BodyFormat::Json, inm).await
+}
+
 #[get("/raw/blocks/{height}/coins/{coinid}")]
-pub async fn coins(height: BlockHeight, coinid: CoinID) -> DynReply {
-    generic_fallible_json_option(BACKEND.get_coin_at_height(height, coinid)).await
+pub async fn coins(
+    height: BlockHeight,
+    coinid: CoinID,
+    #[filter = "accept"] accept: Option<String>,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable_option(
+        BACKEND.get_coin_at_height(height, coinid),
+        BodyFormat::Negotiated(accept),
+        inm,
+    )
+    .await
 }
 
 #[get("/raw/blocks/{height}/full")]
-pub async fn block_full(height: BlockHeight) -> DynReply {
-    generic_fallible_json(BACKEND.get_block(height)).await
+pub async fn block_full(
+    height: BlockHeight,
+    #[filter = "accept"] accept: Option<String>,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable(
+        BACKEND.get_block(height),
+        BodyFormat::Negotiated(accept),
+        inm,
+    )
+    .await
 }
 
 #[get("/raw/blocks/{height}/summary")]
-pub async fn block_summary(height: BlockHeight) -> DynReply {
-    generic_fallible_json(BACKEND.get_block_summary(height)).await
+pub async fn block_summary(
+    height: BlockHeight,
+    #[filter = "if_none_match"] inm: Option<String>,
+) -> DynReply {
+    generic_immutable(BACKEND.get_block_summary(height), BodyFormat::Json, inm).await
 }
 
 #[get("/raw/address/{address}")]
-pub async fn address_summary(address: Address) -> DynReply {
-    generic_fallible_json(BACKEND.get_address_summary(address)).await
+pub async fn address_summary(address: Address, qs: Query<ExportQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    match qs.format.unwrap_or(ExportFormat::Json) {
+        ExportFormat::Json => generic_fallible_json(BACKEND.get_address_summary(address)).await,
+        format => {
+            let rows = blocking_rows(move |send| {
+                let ledger = smol::block_on(BACKEND.get_address_ledger(address))?;
+                for entry in ledger.transactions.iter() {
+                    for (denom, delta) in entry.deltas.iter() {
+                        if !send(AddressHistoryRow::new(
+                            entry.height,
+                            entry.txhash,
+                            *denom,
+                            *delta,
+                        )) {
+                            return Ok(());
+                        }
+                    }
+                }
+                Ok(())
+            });
+            generic_fallible(async move { export_response(format, rows) }).await
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BalancesQuery {
+    height: Option<BlockHeight>,
+    date: Option<chrono::DateTime<Utc>>,
+}
+
+#[get("/raw/address/{address}/balances")]
+pub async fn address_balances(address: Address, qs: Query<BalancesQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    let height = qs.height.or_else(|| qs.date.map(datetime_to_height));
+    generic_fallible_json_option(BACKEND.get_address_balances(address, height)).await
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+struct AddressCoinsQuery {
+    status: Option<CoinStatus>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    denom: Option<Denom>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/raw/address/{address}/coins")]
+pub async fn address_coins(address: Address, qs: Query<AddressCoinsQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json(async move {
+        let after = match qs.cursor {
+            Some(cursor) => {
+                let (height, coinid) = cursor.split_once(':').context("malformed cursor")?;
+                Some((height.parse()?, coinid.parse()?))
+            }
+            None => None,
+        };
+        BACKEND
+            .get_address_coins(
+                address,
+                qs.status.unwrap_or(CoinStatus::Unspent),
+                qs.denom,
+                after,
+                qs.limit.unwrap_or(100).clamp(1, 1000),
+            )
+            .await
+    })
+    .await
 }
 
 #[get("/raw/leaderboard/{denom}")]
-pub async fn leaderboard(denom: Denom) -> DynReply {
-    generic_fallible_json(BACKEND.get_leaderboard(denom)).await
+pub async fn leaderboard(denom: Denom, qs: Query<ExportQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    match qs.format.unwrap_or(ExportFormat::Json) {
+        ExportFormat::Json => generic_fallible_json(BACKEND.get_leaderboard(denom)).await,
+        format => {
+            let rows = blocking_rows(move |send| {
+                let holders = smol::block_on(BACKEND.get_holders(denom))?;
+                // biggest holders first, like the leaderboard page
+                let mut order = holders
+                    .iter()
+                    .map(|(address, balance)| (*balance, *address))
+                    .collect::<Vec<_>>();
+                order.sort_unstable_by(|a, b| b.cmp(a));
+                for (balance, address) in order {
+                    if !send(HolderRow { address, balance }) {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            generic_fallible(async move { export_response(format, rows) }).await
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BatchRequest {
+    queries: Vec<BatchQuery>,
+}
+
+fn batch_body() -> impl warp::Filter<Extract = (BatchRequest,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(MAX_BATCH_BODY_BYTES).and(warp::body::json())
+}
+
+#[post("/raw/batch")]
+pub async fn batch(
+    #[filter = "batch_body"] req: BatchRequest,
+    #[filter = "client"] client: Option<ClientId>,
+) -> DynReply {
+    if req.queries.len() > MAX_BATCH_SIZE {
+        return Ok(Box::new(rweb::reply::with_status(
+            rweb::reply::json(&serde_json::json!({
+                "error": format!("a batch can have at most {} queries", MAX_BATCH_SIZE)
+            })),
+            rweb::hyper::StatusCode::BAD_REQUEST,
+        )));
+    }
+    // every query costs as much as a request, and the request itself already paid for one
+    if let Some(client) = client {
+        let extra = req.queries.len().saturating_sub(1) as u32;
+        if let Err(retry_after) = RATE_LIMITER.check_n(client, RouteClass::Expensive, extra) {
+            return Ok(rate_limited(retry_after));
+        }
+    }
+    generic_fallible_json(async move { anyhow::Ok(run_batch(req.queries).await) }).await
+}
+
+fn graphql_body(
+) -> impl warp::Filter<Extract = (async_graphql::Request,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(MAX_QUERY_BYTES).and(warp::body::json())
+}
+
+#[post("/graphql")]
+pub async fn graphql(#[filter = "graphql_body"] req: async_graphql::Request) -> DynReply {
+    Ok(Box::new(rweb::reply::json(&SCHEMA.execute(req).await)))
+}
+
+#[get("/raw/admin/state")]
+pub async fn admin_state() -> DynReply {
+    generic_fallible_json(async move {
+        Ok(serde_json::json!({
+            "backend": BACKEND.state().await?,
+            "graph_points_cached": GRAPH_CACHE.len(),
+            "blocks_cached": BLOCK_MEMORY_CACHE.entry_count(),
+            "crawls_cached": CRAWL_CACHE.entry_count(),
+            "spends_cached": SPEND_CACHE.entry_count(),
+            "api_keys": API_KEYS.list().len(),
+        }))
+    })
+    .await
+}
+
+#[post("/raw/admin/caches/flush")]
+pub async fn admin_flush_caches() -> DynReply {
+    generic_fallible_json(async move {
+        BACKEND.flush_caches();
+        GRAPH_CACHE.clear();
+        BLOCK_MEMORY_CACHE.invalidate_all();
+        CRAWL_CACHE.invalidate_all();
+        SPEND_CACHE.invalidate_all();
+        info!("flushed in-memory caches");
+        anyhow::Ok(true)
+    })
+    .await
+}
+
+#[get("/raw/admin/keys")]
+pub async fn admin_keys_list() -> DynReply {
+    generic_fallible_json(async move { anyhow::Ok(API_KEYS.list()) }).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct NewKeyRequest {
+    name: String,
+    scopes: BTreeSet<Scope>,
+}
+
+#[post("/raw/admin/keys")]
+pub async fn admin_keys_create(#[json] req: NewKeyRequest) -> DynReply {
+    generic_fallible_json(API_KEYS.create(req.name, req.scopes)).await
+}
+
+#[delete("/raw/admin/keys/{id}")]
+pub async fn admin_keys_revoke(id: u64) -> DynReply {
+    generic_fallible_json_option(async move {
+        Ok(if API_KEYS.revoke(id).await? {
+            Some(id)
+        } else {
+            None
+        })
+    })
+    .await
+}
+
+/// The watchlist, or an error if there is no local database to keep it in.
+fn watchlist() -> anyhow::Result<&'static Watchlist> {
+    WATCHLIST
+        .as_ref()
+        .context("watchlists need a local database (--local-db)")
+}
+
+#[get("/raw/watchlist")]
+pub async fn watchlist_list() -> DynReply {
+    generic_fallible_json(async { watchlist()?.list_watches().await }).await
+}
+
+#[post("/raw/watchlist")]
+pub async fn watchlist_add(#[json] rule: WatchRule) -> DynReply {
+    generic_fallible_json(async { watchlist()?.add_watch(rule).await }).await
+}
+
+#[delete("/raw/watchlist/{id}")]
+pub async fn watchlist_remove(id: u64) -> DynReply {
+    generic_fallible_json_option(async move {
+        Ok(if watchlist()?.remove_watch(id).await? {
+            Some(id)
+        } else {
+            None
+        })
+    })
+    .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DeliveryQuery {
+    watch: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[get("/raw/watchlist/deliveries")]
+pub async fn watchlist_deliveries(qs: Query<DeliveryQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json(async {
+        watchlist()?
+            .list_deliveries(qs.watch, qs.limit.unwrap_or(100).min(1000))
+            .await
+    })
+    .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DisassembleQuery {
+    /// Hex-encoded covenant bytes.
+    covenant: String,
+}
+
+#[post("/raw/covenants/disassemble")]
+pub async fn covenant_disassemble(#[json] qs: DisassembleQuery) -> DynReply {
+    generic_fallible_json(async move { disassemble(&hex::decode(qs.covenant.trim())?) }).await
+}
+
+#[get("/raw/covenants/{covhash}")]
+pub async fn covenant(covhash: Address) -> DynReply {
+    generic_fallible_json_option(BACKEND.get_covenant(covhash)).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StakesQuery {
+    height: Option<BlockHeight>,
+}
+
+#[get("/raw/stakes")]
+pub async fn stakes(qs: Query<StakesQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json_option(BACKEND.get_active_stakes(qs.height)).await
+}
+
+#[get("/raw/stakes/epochs")]
+pub async fn stake_epochs() -> DynReply {
+    generic_fallible_json(BACKEND.get_epoch_stakes()).await
+}
+
+#[get("/raw/stakers/{pubkey}")]
+pub async fn staker(pubkey: tmelcrypt::Ed25519PK) -> DynReply {
+    generic_fallible_json(BACKEND.get_staker_history(pubkey)).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ProposerQuery {
+    start: Option<BlockHeight>,
+    end: Option<BlockHeight>,
+    limit: Option<usize>,
+}
+
+#[get("/raw/proposers")]
+pub async fn proposer_leaderboard(qs: Query<ProposerQuery>) -> DynReply {
+    let qs = qs.into_inner();
+    generic_fallible_json(BACKEND.get_proposer_leaderboard(
+        qs.start,
+        qs.end,
+        qs.limit.unwrap_or(100).min(1000),
+    ))
+    .await
+}
+
+#[get("/raw/proposers/{address}")]
+pub async fn proposer(address: Address) -> DynReply {
+    generic_fallible_json_option(BACKEND.get_proposer(address)).await
 }
 
+#[get("/raw/epochs")]
+pub async fn epochs() -> DynReply {
+    generic_fallible_json(BACKEND.get_epochs()).await
+}
+
+#[get("/raw/epochs/{epoch}")]
+pub async fn epoch(epoch: u64) -> DynReply {
+    generic_fallible_json_option(BACKEND.get_epoch(epoch)).await
+}
+
+/// Graph data that has already been computed.
+static GRAPH_CACHE: Lazy<DashMap<(GraphId, BlockHeight), GraphPoint>> = Lazy::new(DashMap::new);
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 struct GraphQuery {
     id: GraphId,
@@ -174,11 +913,23 @@ enum GraphId {
         #[serde(with = "serde_with::rust::display_fromstr")]
         denom: Denom,
     },
+
+    /// How much of a denomination one address held over time.
+    AddressBalance {
+        #[serde(with = "serde_with::rust::display_fromstr")]
+        address: Address,
+        #[serde(with = "serde_with::rust::display_fromstr")]
+        denom: Denom,
+    },
+
+    /// Total proposer rewards per epoch, one datum at the start of each epoch.
+    ProposerRewards,
 }
 
 #[post("/raw/graph")]
-pub async fn graph(#[json] qs: GraphQuery) -> DynReply {
-    generic_fallible_json_option(async move {
+pub async fn graph(#[json] qs: GraphQuery, export: Query<ExportQuery>) -> DynReply {
+    let export = export.into_inner();
+    let data = async move {
         let snapshot = CLIENT.latest_snapshot().await?;
         let start = qs
             .start
@@ -189,79 +940,112 @@ pub async fn graph(#[json] qs: GraphQuery) -> DynReply {
             .end
             .map(datetime_to_height)
             .unwrap_or_else(|| snapshot.current_header().height);
-        static GRAPH_CACHE: Lazy<DashMap<(GraphId, BlockHeight), f64>> = Lazy::new(DashMap::new);
         // figure out *which* graph to draw
         let load_cache = move |height| GRAPH_CACHE.get(&(qs.id, height)).map(|s| *s);
         let store_cache = move |height, res| {
             GRAPH_CACHE.insert((qs.id, height), res);
         };
-        Ok(Some(match qs.id {
-            GraphId::PoolPrice { from, to } => {
-                graph_range(
-                    start,
-                    end,
-                    1000,
-                    move |height| async move {
-                        let snap = CLIENT.snapshot(height).await?;
-                        let pool_key = PoolKey::new(from, to);
-                        let pool_info = snap.get_pool(pool_key).await?;
-                        if let Some(pool_info) = pool_info {
-                            let ratio = pool_info.implied_price().to_f64().unwrap_or(f64::NAN);
-                            if pool_key.left() == from {
-                                Ok(1.0 / ratio)
-                            } else {
-                                Ok(ratio)
-                            }
+        anyhow::Ok(match qs.id {
+            GraphId::PoolPrice { from, to } => graph_range(
+                start,
+                end,
+                1000,
+                move |height| async move {
+                    let pool_key = PoolKey::new(from, to);
+                    let pool_info =
+                        upstream(async { CLIENT.snapshot(height).await?.get_pool(pool_key).await })
+                            .await?;
+                    if let Some(pool_info) = pool_info {
+                        let ratio = pool_info.implied_price().to_f64().unwrap_or(f64::NAN);
+                        if pool_key.left() == from {
+                            Ok(GraphPoint::value(1.0 / ratio))
                         } else {
-                            Ok(f64::NAN)
+                            Ok(GraphPoint::value(ratio))
                         }
-                    },
-                    load_cache,
-                    store_cache,
-                )
-                .await?
+                    } else {
+                        Ok(GraphPoint::missing())
+                    }
+                },
+                load_cache,
+                store_cache,
+            )
+            .boxed(),
+            GraphId::PoolLiquidity { from, to } => graph_range(
+                start,
+                end,
+                1000,
+                move |height| async move {
+                    let pool_key = PoolKey::new(from, to);
+                    let pool_info =
+                        upstream(async { CLIENT.snapshot(height).await?.get_pool(pool_key).await })
+                            .await?;
+                    if let Some(pool_info) = pool_info {
+                        Ok(GraphPoint::value(
+                            (pool_info.liq_constant() as f64).sqrt() / 1_000_000.0,
+                        ))
+                    } else {
+                        Ok(GraphPoint::missing())
+                    }
+                },
+                load_cache,
+                store_cache,
+            )
+            .boxed(),
+            GraphId::CoinSupply { denom } => graph_range(
+                start,
+                end.min(BACKEND.indexed_highest()),
+                300,
+                move |height| async move {
+                    let v = BACKEND
+                        .get_coin_supply(height, denom)
+                        .await?
+                        .map(GraphPoint::amount)
+                        .unwrap_or_else(GraphPoint::missing);
+                    Ok(v)
+                },
+                load_cache,
+                store_cache,
+            )
+            .boxed(),
+            GraphId::AddressBalance { address, denom } => graph_range(
+                start,
+                end.min(BACKEND.indexed_highest()),
+                300,
+                move |height| async move {
+                    let balances = BACKEND
+                        .get_address_ledger(address)
+                        .await?
+                        .balances_at(height);
+                    let v = balances.get(&denom).copied().unwrap_or_default();
+                    Ok(GraphPoint::amount(v))
+                },
+                load_cache,
+                store_cache,
+            )
+            .boxed(),
+            GraphId::ProposerRewards => {
+                let rewards = BACKEND.get_epoch_rewards(start, end).await?;
+                stream::iter(rewards.into_iter().map(move |epoch| {
+                    let height = BlockHeight(epoch.epoch * STAKE_EPOCH).max(start);
+                    anyhow::Ok(GraphDatum::new(
+                        height,
+                        GraphPoint::amount(epoch.total_rewards),
+                    ))
+                }))
+                .boxed()
             }
-            GraphId::PoolLiquidity { from, to } => {
-                graph_range(
-                    start,
-                    end,
-                    1000,
-                    move |height| async move {
-                        let snap = CLIENT.snapshot(height).await?;
-                        let pool_key = PoolKey::new(from, to);
-                        let pool_info = snap.get_pool(pool_key).await?;
-                        if let Some(pool_info) = pool_info {
-                            Ok((pool_info.liq_constant() as f64).sqrt() / 1_000_000.0)
-                        } else {
-                            Ok(f64::NAN)
-                        }
-                    },
-                    load_cache,
-                    store_cache,
-                )
-                .await?
-            }
-            GraphId::CoinSupply { denom } => {
-                graph_range(
-                    start,
-                    end.min(BACKEND.indexed_highest()),
-                    300,
-                    move |height| async move {
-                        let v = BACKEND
-                            .get_coin_supply(height, denom)
-                            .await?
-                            .map(|c| (c.0 as f64) / 1_000_000.0)
-                            .unwrap_or(f64::NAN);
-                        Ok(v)
-                    },
-                    load_cache,
-                    store_cache,
-                )
-                .await?
-            }
-        }))
-    })
-    .await
+        })
+    };
+    match export.format.unwrap_or(ExportFormat::Json) {
+        ExportFormat::Json => {
+            generic_fallible_json_option(async move {
+                Ok(Some(data.await?.try_collect::<Vec<_>>().await?))
+            })
+            .await
+        }
+        // the data are sent as they are resolved
+        format => generic_fallible(async move { export_response(format, data.await?) }).await,
+    }
 }
 
 #[derive(Debug)]
@@ -296,8 +1080,6 @@ impl Serialize for MicroUnit {
     }
 }
 
-type OpCodeString = String;
-type OpCodeStrings = Vec<OpCodeString>;
 type Inputs = Vec<(usize, CoinID, CoinDataHeight, MicroUnit, String, String)>;
 type Outputs = Vec<(usize, CoinData, MicroUnit, String, String)>;
 #[derive(Serialize, Debug)]
@@ -317,21 +1099,14 @@ struct TransactionTemplate {
     net_gain: BTreeMap<String, Vec<MicroUnit>>,
     gross_gain: Vec<MicroUnit>,
     weight: u128,
-    covenants: Vec<(String, OpCodeStrings)>,
-}
-
-fn decode_all_ops(covenant: Vec<u8>) -> anyhow::Result<OpCodeStrings> {
-    let mut opcode_cursor: Cursor<Vec<u8>> = Cursor::new(covenant);
-    let mut ops: Vec<OpCodeString> = vec![];
-    while opcode_cursor.has_remaining() {
-        let opcode = opcode::OpCode::decode(&mut opcode_cursor)?;
-        let fmt = format!("{opcode:?}")
-            .replace('(', " ")
-            .replace(')', "")
-            .replace(',', " ");
-        ops.push(fmt);
-    }
-    Ok(ops)
+    covenants: Vec<CovenantTemplate>,
+}
+
+#[derive(Serialize, Debug)]
+struct CovenantTemplate {
+    #[serde(flatten)]
+    disassembly: Disassembly,
+    class: CovenantClass,
 }
 
 #[get("/raw/blocks/{height}/{txhash}")]
@@ -445,10 +1220,10 @@ pub async fn transaction_page(height: BlockHeight, txhash: TxHash) -> DynReply {
             .covenants
             .into_iter()
             .map(|cov| {
-                anyhow::Ok((
-                    Address(cov.hash()).to_string(),
-                    decode_all_ops(cov.to_vec())?,
-                ))
+                anyhow::Ok(CovenantTemplate {
+                    disassembly: disassemble(&cov)?,
+                    class: classify(&cov),
+                })
             })
             .collect::<Result<_, _>>()?;
 
@@ -0,0 +1,279 @@
+use chrono::Utc;
+use futures_util::{Stream, StreamExt};
+use melstructs::{Address, BlockHeight, CoinValue, Denom, TxHash};
+use rweb::hyper::{header::CONTENT_TYPE, Body, Response};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    chainidx::TxRecord,
+    graphs::{height_to_datetime, GraphDatum},
+};
+
+/// How many rows a blocking producer can get ahead of the client reading an export.
+const EXPORT_BUFFER: usize = 1024;
+
+/// How an endpoint's results are returned.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// The endpoint's usual JSON response.
+    Json,
+    /// Comma-separated values, with a header row.
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+}
+
+/// The `format` query parameter of routes that can export their results.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<ExportFormat>,
+}
+
+/// Whether a query string asks for a CSV or NDJSON export rather than JSON. The query is decoded the same way the routes decode it, so percent-encoding cannot hide an export.
+pub fn is_export(query: &str) -> bool {
+    serde_urlencoded::from_str::<ExportQuery>(query)
+        .ok()
+        .and_then(|q| q.format)
+        .map(|format| format != ExportFormat::Json)
+        .unwrap_or(false)
+}
+
+/// A flat record that can be exported as a CSV row or an NDJSON line. Amounts should be exact integers in micro-units and dates in ISO 8601.
+pub trait ExportRow: Serialize {
+    /// The column names, in order.
+    fn columns() -> &'static [&'static str];
+
+    /// The row's values, in the same order as the columns.
+    fn values(&self) -> Vec<String>;
+}
+
+/// How one transaction changed an address's balance in one denomination.
+#[serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct AddressHistoryRow {
+    pub height: BlockHeight,
+    pub date: chrono::DateTime<Utc>,
+    pub txhash: TxHash,
+    #[serde_as(as = "DisplayFromStr")]
+    pub denom: Denom,
+    pub delta: i128,
+}
+
+impl AddressHistoryRow {
+    pub fn new(height: BlockHeight, txhash: TxHash, denom: Denom, delta: i128) -> Self {
+        Self {
+            height,
+            date: height_to_datetime(height),
+            txhash,
+            denom,
+            delta,
+        }
+    }
+}
+
+impl ExportRow for AddressHistoryRow {
+    fn columns() -> &'static [&'static str] {
+        &["height", "date", "txhash", "denom", "delta"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.height.to_string(),
+            self.date.to_rfc3339(),
+            self.txhash.to_string(),
+            self.denom.to_string(),
+            self.delta.to_string(),
+        ]
+    }
+}
+
+/// An address and how much of a denomination it holds.
+#[serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct HolderRow {
+    #[serde_as(as = "DisplayFromStr")]
+    pub address: Address,
+    pub balance: CoinValue,
+}
+
+impl ExportRow for HolderRow {
+    fn columns() -> &'static [&'static str] {
+        &["address", "balance"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.address.to_string(), self.balance.0.to_string()]
+    }
+}
+
+impl ExportRow for GraphDatum {
+    fn columns() -> &'static [&'static str] {
+        &["height", "date", "value", "amount"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.height.to_string(),
+            self.date.to_rfc3339(),
+            // missing data is left blank rather than written as NaN
+            if self.value.is_nan() {
+                String::new()
+            } else {
+                self.value.to_string()
+            },
+            self.amount.map(|v| v.0.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+/// A transaction search result, along with its date.
+#[derive(Serialize, Clone, Debug)]
+pub struct TxRow {
+    #[serde(flatten)]
+    pub record: TxRecord,
+    pub date: chrono::DateTime<Utc>,
+}
+
+impl From<TxRecord> for TxRow {
+    fn from(record: TxRecord) -> Self {
+        Self {
+            date: height_to_datetime(record.height),
+            record,
+        }
+    }
+}
+
+impl ExportRow for TxRow {
+    fn columns() -> &'static [&'static str] {
+        &["height", "date", "txhash", "kind", "fee", "value"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.record.height.to_string(),
+            self.date.to_rfc3339(),
+            self.record.txhash.to_string(),
+            self.record.kind.to_string(),
+            self.record.fee.0.to_string(),
+            self.record
+                .value
+                .map(|v| v.0.to_string())
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+/// Builds a streaming response out of rows, which are only rendered as the client reads them, so rows that are fetched lazily are also only fetched as the client reads them. JSON is not an export format, so it is rendered as NDJSON.
+pub fn export_response<R: ExportRow + Send + 'static>(
+    format: ExportFormat,
+    rows: impl Stream<Item = anyhow::Result<R>> + Send + 'static,
+) -> anyhow::Result<Response<Body>> {
+    let body = match format {
+        ExportFormat::Csv => {
+            let header = futures_util::stream::once(async { anyhow::Ok(csv_line(R::columns())) });
+            let rows = rows.map(|row| row.map(|row| csv_line(&row.values())));
+            Body::wrap_stream(header.chain(rows))
+        }
+        ExportFormat::Json | ExportFormat::Ndjson => Body::wrap_stream(rows.map(|row| {
+            let mut line = serde_json::to_string(&row?)?;
+            line.push('\n');
+            anyhow::Ok(line)
+        })),
+    };
+    let content_type = match format {
+        ExportFormat::Csv => "text/csv; charset=utf-8",
+        ExportFormat::Json | ExportFormat::Ndjson => "application/x-ndjson",
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(body)?)
+}
+
+/// Streams the rows that a blocking function produces, like rows read off the indexer. The producer runs on the blocking thread pool and hands the rows over through a bounded channel, so it never gets more than a few rows ahead of the client, and stops once the client goes away. It is given a function that sends one row, which returns false once nobody is reading anymore.
+pub fn blocking_rows<R: Send + 'static>(
+    produce: impl FnOnce(&mut dyn FnMut(R) -> bool) -> anyhow::Result<()> + Send + 'static,
+) -> impl Stream<Item = anyhow::Result<R>> + Send + 'static {
+    let (send, recv) = smol::channel::bounded(EXPORT_BUFFER);
+    smol::unblock(move || {
+        if let Err(err) = produce(&mut |row| send.send_blocking(Ok(row)).is_ok()) {
+            let _ = send.send_blocking(Err(err));
+        }
+    })
+    .detach();
+    recv
+}
+
+fn csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| csv_escape(field.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Quotes a field if it contains anything that would otherwise break the row, as per RFC 4180.
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_encoded_exports() {
+        assert!(is_export("format=csv"));
+        assert!(is_export("limit=5&format=%6Edjson"));
+        assert!(is_export("format=cs%76"));
+        assert!(!is_export("format=json"));
+        assert!(!is_export("q=format%3Dcsv"));
+        assert!(!is_export(""));
+    }
+
+    #[test]
+    fn blocking_rows_stop_when_the_client_leaves() {
+        let (done_send, done_recv) = std::sync::mpsc::channel();
+        let mut rows = blocking_rows(move |send| {
+            let mut sent = 0;
+            while sent < 1_000_000 && send(sent) {
+                sent += 1;
+            }
+            done_send.send(sent).unwrap();
+            anyhow::bail!("should not be read")
+        });
+        smol::block_on(async {
+            assert_eq!(rows.next().await.unwrap().unwrap(), 0);
+            assert_eq!(rows.next().await.unwrap().unwrap(), 1);
+        });
+        drop(rows);
+        let sent = done_recv.recv().unwrap();
+        assert!(sent <= EXPORT_BUFFER + 2);
+
+        // errors end the stream
+        let rows = smol::block_on(
+            blocking_rows(|send: &mut dyn FnMut(u32) -> bool| {
+                send(1);
+                anyhow::bail!("broken")
+            })
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(rows.len(), 2);
+        assert!(rows[1].is_err());
+    }
+
+    #[test]
+    fn csv_quotes_only_when_needed() {
+        assert_eq!(
+            csv_line(&["a", "b,c", "say \"hi\""]),
+            "a,\"b,c\",\"say \"\"hi\"\"\"\r\n"
+        );
+    }
+}
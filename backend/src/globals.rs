@@ -1,65 +1,55 @@
-use std::{net::SocketAddr, path::PathBuf};
-
 use melblkidx::Indexer;
 use melnet2::{wire::http::HttpBackhaul, Backhaul};
 use melprot::{Client, NodeRpcClient};
 use melstructs::NetID;
 use once_cell::sync::Lazy;
-use structopt::StructOpt;
-
-use crate::backend::Backend;
-
-#[derive(StructOpt)]
-pub struct Args {
-    #[structopt(long)]
-    /// Where to listen for incoming REST API calls
-    pub listen: SocketAddr,
-
-    #[structopt(long)]
-    /// A full node to connect to
-    connect: SocketAddr,
-
-    #[structopt(long)]
-    /// The custom network to connect to
-    network: Option<NetID>,
 
-    #[structopt(long)]
-    /// Whether or not the block explorer is connected to a testnet node.
-    testnet: bool,
+use crate::{
+    apikeys::ApiKeys,
+    backend::Backend,
+    blockcache::BlockCache,
+    chainidx::ChainIndex,
+    config::Config,
+    localdb::LocalDb,
+    ratelimit::{Budget, RateLimiter},
+    watchlist::Watchlist,
+};
 
-    #[structopt(long)]
-    /// If set, indexes blocks and saves them to the given location.
-    blkidx_db: Option<PathBuf>,
-}
-
-/// Command-line arguments that were initially passed in.
-pub static CMD_ARGS: Lazy<Args> = Lazy::new(Args::from_args);
+/// Melscan's configuration, loaded once at startup. Exits with an explanation if it is invalid.
+pub static CONFIG: Lazy<Config> = Lazy::new(|| match Config::load() {
+    Ok(config) => config,
+    Err(err) => {
+        eprintln!("{:#}", err);
+        std::process::exit(1)
+    }
+});
 
 /// The global Client for talking to the network.
 pub static CLIENT: Lazy<Client> = Lazy::new(|| {
     smolscale::block_on(async move {
         let backhaul = HttpBackhaul::new();
-        let network = if let Some(custom_net) = CMD_ARGS.network {
-            custom_net
-        } else if CMD_ARGS.testnet {
-            NetID::Testnet
-        } else {
-            NetID::Mainnet
-        };
+        let network = CONFIG.upstream.netid();
 
         let client = Client::new(
             network,
             NodeRpcClient(
                 backhaul
-                    .connect(CMD_ARGS.connect.to_string().into())
+                    .connect(
+                        CONFIG
+                            .upstream
+                            .connect
+                            .expect("validated at startup")
+                            .to_string()
+                            .into(),
+                    )
                     .await
                     .unwrap(),
             ),
         );
-        if let Some(_) = CMD_ARGS.network {
-            println!("Insecurely trusting snapshot on a custom network");
+        if CONFIG.upstream.network.is_some() {
+            log::warn!("Insecurely trusting snapshot on a custom network");
             client.dangerously_trust_latest().await.unwrap();
-        } else if CMD_ARGS.testnet {
+        } else if CONFIG.upstream.testnet {
             client.trust(melbootstrap::checkpoint_height(NetID::Testnet).unwrap());
         } else {
             client.trust(melbootstrap::checkpoint_height(NetID::Mainnet).unwrap());
@@ -71,9 +61,54 @@ pub static CLIENT: Lazy<Client> = Lazy::new(|| {
 pub static BACKEND: Lazy<Backend> = Lazy::new(|| {
     Backend::new(
         CLIENT.clone(),
-        CMD_ARGS
+        CONFIG
+            .storage
             .blkidx_db
             .as_ref()
             .map(|path| Indexer::new(path, CLIENT.clone()).unwrap()),
+        CONFIG
+            .storage
+            .local_db
+            .as_ref()
+            .map(|_| ChainIndex::new(LOCAL_DB.clone(), CLIENT.clone()).unwrap()),
+        &CONFIG.cache,
+    )
+});
+
+/// Melscan's own local database, kept in memory if there is no `--local-db`. Whatever needs to outlive a restart, like API keys, is refused at startup in that case.
+pub static LOCAL_DB: Lazy<LocalDb> =
+    Lazy::new(|| LocalDb::open(CONFIG.storage.local_db.as_deref()).unwrap());
+
+/// The global watchlist, if we have a local database on disk. Watches and pending deliveries must survive restarts, so they are never kept in memory only.
+pub static WATCHLIST: Lazy<Option<Watchlist>> =
+    Lazy::new(|| {
+        CONFIG.storage.local_db.as_ref().map(|_| {
+            Watchlist::new(LOCAL_DB.clone(), CONFIG.watchlist.webhook_url.clone()).unwrap()
+        })
+    });
+
+/// The durable block cache, if we have a local database on disk.
+pub static BLOCK_CACHE: Lazy<Option<BlockCache>> = Lazy::new(|| {
+    CONFIG
+        .storage
+        .local_db
+        .as_ref()
+        .map(|_| BlockCache::new(LOCAL_DB.clone()).unwrap())
+});
+
+/// The API key store.
+pub static API_KEYS: Lazy<ApiKeys> =
+    Lazy::new(|| ApiKeys::new(LOCAL_DB.clone(), &CONFIG.auth.admin_keys).unwrap());
+
+/// The global rate limiter.
+pub static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(|| {
+    let budget = |per_minute| Budget { per_minute };
+    let limits = &CONFIG.rate_limits;
+    RateLimiter::new(
+        [budget(limits.per_ip), budget(limits.expensive_per_ip)],
+        [
+            budget(limits.per_api_key),
+            budget(limits.expensive_per_api_key),
+        ],
     )
 });
@@ -0,0 +1,562 @@
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::Context as _;
+use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+use futures_util::{future::try_join_all, StreamExt};
+use melstructs::{
+    Address, Block, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, PoolKey,
+    PoolState, Transaction, TxHash,
+};
+use num_traits::ToPrimitive;
+use once_cell::sync::Lazy;
+use smol::lock::OnceCell;
+
+use crate::{
+    backend::{AddressLedger, CoinStatus},
+    globals::{BACKEND, CLIENT},
+    upstream::upstream,
+    utils::get_blocks,
+};
+
+/// Deepest a query can nest.
+const MAX_DEPTH: usize = 10;
+
+/// At most how large a request to `/graphql` can be.
+pub const MAX_QUERY_BYTES: u64 = 64 * 1024;
+
+/// Most fields a query can ask for, counting every item of a list field as a separate field and calls to the full node as several.
+const MAX_COMPLEXITY: usize = 1000;
+
+/// What a field that loads something from the full node costs on top of its children.
+const UPSTREAM_COST: usize = 10;
+
+/// How many items a list field without a `limit`, like a block's transactions, is assumed to have.
+const LIST_ESTIMATE: usize = 20;
+
+pub type ExplorerSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// The schema served at `/graphql`.
+pub static SCHEMA: Lazy<ExplorerSchema> = Lazy::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+});
+
+/// GraphQL integers are 32-bit, so amounts are returned as strings of micro-units.
+fn amount(value: CoinValue) -> String {
+    value.0.to_string()
+}
+
+fn parse<T: FromStr>(s: &str, what: &str) -> anyhow::Result<T> {
+    s.trim()
+        .parse()
+        .ok()
+        .with_context(|| format!("invalid {}: {}", what, s))
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A block, if it has been confirmed.
+    async fn block(&self, height: u64) -> async_graphql::Result<Option<BlockNode>> {
+        BlockNode::load(BlockHeight(height)).await
+    }
+
+    /// The latest confirmed block.
+    async fn latest_block(&self) -> async_graphql::Result<BlockNode> {
+        let height = CLIENT.latest_snapshot().await?.current_header().height;
+        BlockNode::load(height)
+            .await?
+            .ok_or_else(|| "latest block not found".into())
+    }
+
+    /// A confirmed transaction. Without a height, the transaction is searched for first.
+    #[graphql(complexity = "UPSTREAM_COST + child_complexity")]
+    async fn transaction(
+        &self,
+        txhash: String,
+        height: Option<u64>,
+    ) -> async_graphql::Result<Option<TransactionNode>> {
+        let txhash: TxHash = parse(&txhash, "transaction hash")?;
+        let height = match height {
+            Some(height) => BlockHeight(height),
+            None => match BACKEND.search_transaction(txhash).await? {
+                Some(height) => height,
+                None => return Ok(None),
+            },
+        };
+        Ok(BACKEND
+            .get_transaction_at_height(height, txhash)
+            .await?
+            .map(|tx| TransactionNode { tx, height }))
+    }
+
+    /// A coin, as of some height.
+    async fn coin(&self, coinid: String, height: u64) -> async_graphql::Result<Option<CoinNode>> {
+        let coinid: CoinID = parse(&coinid, "coin ID")?;
+        Ok(BACKEND
+            .get_coin_at_height(BlockHeight(height), coinid)
+            .await?
+            .map(|data| CoinNode { coinid, data }))
+    }
+
+    async fn address(&self, address: String) -> async_graphql::Result<AddressNode> {
+        Ok(AddressNode::new(parse(&address, "address")?))
+    }
+
+    /// A Melswap pool, as of some height, defaulting to the latest.
+    async fn pool(
+        &self,
+        left: String,
+        right: String,
+        height: Option<u64>,
+    ) -> async_graphql::Result<Option<PoolNode>> {
+        let left: Denom = parse(&left, "denomination")?;
+        let right: Denom = parse(&right, "denomination")?;
+        if left == right {
+            return Err("a pool needs two different denominations".into());
+        }
+        PoolNode::load(PoolKey::new(left, right), height.map(BlockHeight)).await
+    }
+
+    async fn denom(&self, denom: String) -> async_graphql::Result<DenomNode> {
+        Ok(DenomNode {
+            denom: parse(&denom, "denomination")?,
+        })
+    }
+}
+
+pub struct BlockNode {
+    block: Block,
+    reward: CoinValue,
+}
+
+impl BlockNode {
+    /// Loads a block along with its proposer reward, through the block caches.
+    async fn load(height: BlockHeight) -> async_graphql::Result<Option<Self>> {
+        let snap = CLIENT.latest_snapshot().await?;
+        if height > snap.current_header().height {
+            return Ok(None);
+        }
+        let (block, reward) = get_blocks(&snap, std::iter::once(height.0))
+            .next()
+            .await
+            .context("no block fetched")??;
+        Ok(Some(BlockNode { block, reward }))
+    }
+}
+
+#[Object(name = "Block")]
+impl BlockNode {
+    async fn height(&self) -> u64 {
+        self.block.header.height.0
+    }
+
+    async fn hash(&self) -> String {
+        self.block.header.hash().to_string()
+    }
+
+    async fn previous_hash(&self) -> String {
+        self.block.header.previous.to_string()
+    }
+
+    async fn fee_pool(&self) -> String {
+        amount(self.block.header.fee_pool)
+    }
+
+    async fn fee_multiplier(&self) -> f64 {
+        self.block.header.fee_multiplier as f64 / 65536.0
+    }
+
+    async fn total_fees(&self) -> String {
+        amount(self.block.transactions.iter().map(|tx| tx.fee).sum())
+    }
+
+    /// The reward paid to the block's proposer.
+    async fn reward(&self) -> String {
+        amount(self.reward)
+    }
+
+    #[graphql(complexity = "UPSTREAM_COST + child_complexity")]
+    async fn previous(&self) -> async_graphql::Result<Option<BlockNode>> {
+        if self.block.header.height.0 == 0 {
+            return Ok(None);
+        }
+        BlockNode::load(BlockHeight(self.block.header.height.0 - 1)).await
+    }
+
+    #[graphql(complexity = "LIST_ESTIMATE * child_complexity")]
+    async fn transactions(&self) -> Vec<TransactionNode> {
+        self.block
+            .transactions
+            .iter()
+            .map(|tx| TransactionNode {
+                tx: tx.clone(),
+                height: self.block.header.height,
+            })
+            .collect()
+    }
+}
+
+pub struct TransactionNode {
+    tx: Transaction,
+    height: BlockHeight,
+}
+
+#[Object(name = "Transaction")]
+impl TransactionNode {
+    async fn txhash(&self) -> String {
+        self.tx.hash_nosigs().to_string()
+    }
+
+    async fn height(&self) -> u64 {
+        self.height.0
+    }
+
+    async fn kind(&self) -> String {
+        self.tx.kind.to_string()
+    }
+
+    async fn fee(&self) -> String {
+        amount(self.tx.fee)
+    }
+
+    /// Hex-encoded.
+    async fn data(&self) -> String {
+        hex::encode(&self.tx.data)
+    }
+
+    #[graphql(complexity = "UPSTREAM_COST + child_complexity")]
+    async fn block(&self) -> async_graphql::Result<Option<BlockNode>> {
+        BlockNode::load(self.height).await
+    }
+
+    /// The coins this transaction spent, as they were just before it.
+    #[graphql(complexity = "LIST_ESTIMATE * (UPSTREAM_COST + child_complexity)")]
+    async fn inputs(&self) -> async_graphql::Result<Vec<CoinNode>> {
+        let snap = upstream(CLIENT.snapshot(self.height)).await?;
+        let coins = try_join_all(self.tx.inputs.iter().map(|coinid| {
+            let snap = &snap;
+            async move {
+                let data = upstream(snap.get_coin_spent_here(*coinid))
+                    .await?
+                    .with_context(|| format!("input {} not found", coinid))?;
+                anyhow::Ok(CoinNode {
+                    coinid: *coinid,
+                    data,
+                })
+            }
+        }))
+        .await?;
+        Ok(coins)
+    }
+
+    #[graphql(complexity = "LIST_ESTIMATE * child_complexity")]
+    async fn outputs(&self) -> Vec<CoinNode> {
+        self.tx
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(i, coin_data)| CoinNode {
+                coinid: self.tx.output_coinid(i as u8),
+                data: CoinDataHeight {
+                    coin_data: coin_data.clone(),
+                    height: self.height,
+                },
+            })
+            .collect()
+    }
+}
+
+pub struct CoinNode {
+    coinid: CoinID,
+    data: CoinDataHeight,
+}
+
+#[Object(name = "Coin")]
+impl CoinNode {
+    async fn coinid(&self) -> String {
+        self.coinid.to_string()
+    }
+
+    async fn value(&self) -> String {
+        amount(self.data.coin_data.value)
+    }
+
+    async fn denom(&self) -> DenomNode {
+        DenomNode {
+            denom: self.data.coin_data.denom,
+        }
+    }
+
+    async fn address(&self) -> AddressNode {
+        AddressNode::new(self.data.coin_data.covhash)
+    }
+
+    /// Hex-encoded.
+    async fn additional_data(&self) -> String {
+        self.data.coin_data.additional_data_hex()
+    }
+
+    async fn created_height(&self) -> u64 {
+        self.data.height.0
+    }
+
+    /// The transaction that created the coin. Genesis coins have none.
+    #[graphql(complexity = "UPSTREAM_COST + child_complexity")]
+    async fn created_by(&self) -> async_graphql::Result<Option<TransactionNode>> {
+        Ok(BACKEND
+            .get_transaction_at_height(self.data.height, self.coinid.txhash)
+            .await?
+            .map(|tx| TransactionNode {
+                tx,
+                height: self.data.height,
+            }))
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Balance {
+    denom: String,
+    value: String,
+}
+
+pub struct AddressNode {
+    address: Address,
+    /// Loaded by the first field that needs it, and shared by the rest.
+    ledger: OnceCell<Arc<AddressLedger>>,
+}
+
+impl AddressNode {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            ledger: OnceCell::new(),
+        }
+    }
+
+    async fn ledger(&self) -> anyhow::Result<&Arc<AddressLedger>> {
+        self.ledger
+            .get_or_try_init(|| BACKEND.get_address_ledger(self.address))
+            .await
+    }
+}
+
+#[Object(name = "Address")]
+impl AddressNode {
+    async fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Balances as of some height, defaulting to the latest indexed one. Needs the indexer.
+    async fn balances(&self, height: Option<u64>) -> async_graphql::Result<Vec<Balance>> {
+        let height = height
+            .map(BlockHeight)
+            .unwrap_or_else(|| BACKEND.indexed_highest());
+        let ledger = self.ledger().await?;
+        Ok(ledger
+            .balances_at(height)
+            .into_iter()
+            .map(|(denom, value)| Balance {
+                denom: denom.to_string(),
+                value: amount(value),
+            })
+            .collect())
+    }
+
+    /// The most recent transactions touching the address, newest first. Needs the indexer.
+    #[graphql(complexity = "limit as usize * child_complexity")]
+    async fn transactions(
+        &self,
+        #[graphql(default = 20)] limit: u32,
+    ) -> async_graphql::Result<Vec<AddressTransaction>> {
+        let ledger = self.ledger().await?;
+        Ok(ledger
+            .transactions
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .map(|entry| AddressTransaction {
+                txhash: entry.txhash,
+                height: entry.height,
+                deltas: entry
+                    .deltas
+                    .iter()
+                    .map(|(denom, delta)| BalanceDelta {
+                        denom: denom.to_string(),
+                        delta: delta.to_string(),
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// The address's coins, newest first. Needs the indexer.
+    #[graphql(complexity = "limit as usize * child_complexity")]
+    async fn coins(
+        &self,
+        #[graphql(default = true)] unspent: bool,
+        denom: Option<String>,
+        #[graphql(default = 20)] limit: u32,
+    ) -> async_graphql::Result<Vec<CoinNode>> {
+        let denom = match denom {
+            Some(denom) => Some(parse(&denom, "denomination")?),
+            None => None,
+        };
+        let status = if unspent {
+            CoinStatus::Unspent
+        } else {
+            CoinStatus::All
+        };
+        let page = BACKEND
+            .get_address_coins(self.address, status, denom, None, limit as usize)
+            .await?;
+        let coins = page
+            .coins
+            .into_iter()
+            .map(|coin| {
+                anyhow::Ok(CoinNode {
+                    coinid: coin.coinid,
+                    data: CoinDataHeight {
+                        coin_data: CoinData {
+                            covhash: self.address,
+                            value: coin.value,
+                            denom: coin.denom,
+                            additional_data: hex::decode(&coin.additional_data)?.into(),
+                        },
+                        height: coin.create_height,
+                    },
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(coins)
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct BalanceDelta {
+    denom: String,
+    /// Micro-units, negative if the address paid out.
+    delta: String,
+}
+
+pub struct AddressTransaction {
+    txhash: TxHash,
+    height: BlockHeight,
+    deltas: Vec<BalanceDelta>,
+}
+
+#[Object]
+impl AddressTransaction {
+    async fn txhash(&self) -> String {
+        self.txhash.to_string()
+    }
+
+    async fn height(&self) -> u64 {
+        self.height.0
+    }
+
+    async fn deltas(&self) -> Vec<BalanceDelta> {
+        self.deltas.clone()
+    }
+
+    #[graphql(complexity = "UPSTREAM_COST + child_complexity")]
+    async fn transaction(&self) -> async_graphql::Result<Option<TransactionNode>> {
+        Ok(BACKEND
+            .get_transaction_at_height(self.height, self.txhash)
+            .await?
+            .map(|tx| TransactionNode {
+                tx,
+                height: self.height,
+            }))
+    }
+}
+
+pub struct PoolNode {
+    key: PoolKey,
+    state: PoolState,
+    height: BlockHeight,
+}
+
+impl PoolNode {
+    async fn load(
+        key: PoolKey,
+        height: Option<BlockHeight>,
+    ) -> async_graphql::Result<Option<PoolNode>> {
+        let snap = match height {
+            Some(height) => CLIENT.snapshot(height).await?,
+            None => CLIENT.latest_snapshot().await?,
+        };
+        let height = snap.current_header().height;
+        Ok(snap
+            .get_pool(key)
+            .await?
+            .map(|state| PoolNode { key, state, height }))
+    }
+}
+
+#[Object(name = "Pool")]
+impl PoolNode {
+    async fn height(&self) -> u64 {
+        self.height.0
+    }
+
+    async fn left(&self) -> DenomNode {
+        DenomNode {
+            denom: self.key.left(),
+        }
+    }
+
+    async fn right(&self) -> DenomNode {
+        DenomNode {
+            denom: self.key.right(),
+        }
+    }
+
+    async fn lefts(&self) -> String {
+        self.state.lefts.to_string()
+    }
+
+    async fn rights(&self) -> String {
+        self.state.rights.to_string()
+    }
+
+    /// Liquidity tokens issued.
+    async fn liqs(&self) -> String {
+        self.state.liqs.to_string()
+    }
+
+    /// How many rights one left is worth.
+    async fn price(&self) -> Option<f64> {
+        self.state.implied_price().to_f64()
+    }
+}
+
+pub struct DenomNode {
+    denom: Denom,
+}
+
+#[Object(name = "Denom")]
+impl DenomNode {
+    async fn name(&self) -> String {
+        self.denom.to_string()
+    }
+
+    /// The denomination's pool against MEL, as of the latest block.
+    async fn pool(&self) -> async_graphql::Result<Option<PoolNode>> {
+        if self.denom == Denom::Mel {
+            return Ok(None);
+        }
+        PoolNode::load(PoolKey::new(Denom::Mel, self.denom), None).await
+    }
+
+    /// The total supply as of the latest indexed block. Needs the indexer.
+    async fn supply(&self) -> async_graphql::Result<Option<String>> {
+        Ok(BACKEND
+            .get_coin_supply(BACKEND.indexed_highest(), self.denom)
+            .await?
+            .map(amount))
+    }
+}
@@ -1,21 +1,28 @@
 use std::sync::Arc;
 
 use chrono::{TimeZone, Utc};
-use futures_util::Future;
+use futures_util::{Future, Stream};
 use itertools::Itertools;
-use melstructs::BlockHeight;
+use melstructs::{BlockHeight, CoinValue};
 use serde::{Deserialize, Serialize};
 
 mod helpers;
 
+/// How many graph data are resolved at the same time, per graph.
+const GRAPH_CONCURRENCY: usize = 64;
+
+/// Seconds between blocks. Headers carry no timestamps, so converting between heights and dates assumes blocks are produced exactly this often.
+pub const BLOCK_INTERVAL_SECS: u64 = 30;
+
 /// Convert datetime to a block height
 pub fn datetime_to_height(dt: chrono::DateTime<Utc>) -> BlockHeight {
-    BlockHeight((dt.timestamp() as u64).saturating_sub(1618365600) / 30)
+    BlockHeight((dt.timestamp() as u64).saturating_sub(1618365600) / BLOCK_INTERVAL_SECS)
 }
 
 /// Convert block height to a datetime
 pub fn height_to_datetime(height: BlockHeight) -> chrono::DateTime<Utc> {
-    Utc.timestamp((height.0 * 30 + 1618365600) as i64, 0)
+    Utc.timestamp_opt((height.0 * BLOCK_INTERVAL_SECS + 1618365600) as i64, 0)
+        .unwrap()
 }
 
 /// A JSON-friendly graphing datum
@@ -24,43 +31,80 @@ pub struct GraphDatum {
     pub height: BlockHeight,
     pub date: chrono::DateTime<Utc>,
     pub value: f64,
+    /// The exact amount behind the value, in micro-units, for graphs of amounts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<CoinValue>,
+}
+
+impl GraphDatum {
+    pub fn new(height: BlockHeight, point: GraphPoint) -> Self {
+        Self {
+            height,
+            date: height_to_datetime(height),
+            value: point.value,
+            amount: point.amount,
+        }
+    }
+}
+
+/// What a graph resolves to at one height.
+#[derive(Copy, Clone, Debug)]
+pub struct GraphPoint {
+    pub value: f64,
+    pub amount: Option<CoinValue>,
 }
 
-/// Given a range of blocks, and a function that resolves a value given a block height, return a bunch of graph data.
-pub async fn graph_range<F: Future<Output = anyhow::Result<f64>> + Send + 'static>(
+impl GraphPoint {
+    /// A plain value, like a price.
+    pub fn value(value: f64) -> Self {
+        Self {
+            value,
+            amount: None,
+        }
+    }
+
+    /// An amount in micro-units, which is plotted in whole units.
+    pub fn amount(amount: CoinValue) -> Self {
+        Self {
+            value: amount.0 as f64 / 1_000_000.0,
+            amount: Some(amount),
+        }
+    }
+
+    /// No data at this height.
+    pub fn missing() -> Self {
+        Self::value(f64::NAN)
+    }
+}
+
+/// Given a range of blocks, and a function that resolves a value given a block height, return a stream of graph data, in order. Resolutions run concurrently, up to `GRAPH_CONCURRENCY` ahead of whoever reads the stream, so resolvers that call the full node should take upstream permits around those calls. Dropping the stream cancels the resolutions still running.
+pub fn graph_range<F: Future<Output = anyhow::Result<GraphPoint>> + Send + 'static>(
     start: BlockHeight,
     end: BlockHeight,
     approx_count: usize,
     resolve: impl Fn(BlockHeight) -> F + Send + Sync + 'static,
-    cache_get: impl Fn(BlockHeight) -> Option<f64> + 'static,
-    cache_set: impl Fn(BlockHeight, f64) + Send + Sync + 'static,
-) -> anyhow::Result<Vec<GraphDatum>> {
+    cache_get: impl Fn(BlockHeight) -> Option<GraphPoint> + Send + 'static,
+    cache_set: impl Fn(BlockHeight, GraphPoint) + Send + Sync + 'static,
+) -> impl Stream<Item = anyhow::Result<GraphDatum>> + Send + 'static {
     let heights = helpers::interpolate_between(start.0, end.0, approx_count as _).collect_vec();
     let resolve = Arc::new(resolve);
     let cache_set = Arc::new(cache_set);
-    helpers::fast_async_map(heights, |height| {
-        let height = BlockHeight(height);
-        if let Some(cached) = cache_get(height) {
-            Ok(Ok(GraphDatum {
-                value: cached,
-                height,
-                date: height_to_datetime(height),
-            }))
-        } else {
-            let resolve = resolve.clone();
-            let cache_set = cache_set.clone();
-            Err(smolscale::spawn(async move {
-                let res = resolve(height).await?;
-                cache_set(height, res);
-                Ok(GraphDatum {
-                    value: res,
-                    height,
-                    date: height_to_datetime(height),
-                })
-            }))
-        }
-    })
-    .await
-    .into_iter()
-    .collect()
+    helpers::fast_async_stream(
+        heights,
+        move |height| {
+            let height = BlockHeight(height);
+            if let Some(cached) = cache_get(height) {
+                Ok(Ok(GraphDatum::new(height, cached)))
+            } else {
+                let resolve = resolve.clone();
+                let cache_set = cache_set.clone();
+                Err(smolscale::spawn(async move {
+                    let res = resolve(height).await?;
+                    cache_set(height, res);
+                    Ok(GraphDatum::new(height, res))
+                }))
+            }
+        },
+        GRAPH_CONCURRENCY,
+    )
 }
@@ -1,8 +1,7 @@
-use futures_util::stream::FuturesUnordered;
-use futures_util::StreamExt;
+use futures_util::future::{self, Either};
+use futures_util::{stream, Stream, StreamExt};
 use itertools::Itertools;
 use smol::Task;
-use std::collections::BTreeMap;
 
 /// Interpolates between two numbers in a cache-friendly fashion
 pub fn interpolate_between(start: u64, end: u64, approx_count: u64) -> impl Iterator<Item = u64> {
@@ -10,24 +9,18 @@ pub fn interpolate_between(start: u64, end: u64, approx_count: u64) -> impl Iter
     (start..=end).filter(move |i| i % interval == 0).unique()
 }
 
-/// Efficiently map an asynchronous function over a vector, doing any concurrency only when any of the functions need to block (indicated by returning a task instead of an immediate value).
-pub async fn fast_async_map<T, U>(v: Vec<T>, f: impl Fn(T) -> Result<U, Task<U>>) -> Vec<U> {
-    let mut toret = BTreeMap::new();
-    let mut pending = FuturesUnordered::new();
-    for (i, val) in v.into_iter().enumerate() {
-        match f(val) {
-            Ok(res) => {
-                toret.insert(i, res);
-            }
-            Err(fut) => {
-                pending.push(async move { (i, fut.await) });
-            }
-        }
-    }
-    while let Some((i, v)) = pending.next().await {
-        toret.insert(i, v);
-    }
-    toret.into_iter().map(|d| d.1).collect()
+/// Efficiently map an asynchronous function over a vector, in order, doing any concurrency only when any of the functions need to block (indicated by returning a task instead of an immediate value). At most `concurrency` of the tasks run at once, and they are only started as the results are read.
+pub fn fast_async_stream<T: Send + 'static, U: Send + 'static>(
+    v: Vec<T>,
+    f: impl Fn(T) -> Result<U, Task<U>> + Send + 'static,
+    concurrency: usize,
+) -> impl Stream<Item = U> + Send + 'static {
+    stream::iter(v)
+        .map(move |val| match f(val) {
+            Ok(res) => Either::Left(future::ready(res)),
+            Err(task) => Either::Right(task),
+        })
+        .buffered(concurrency)
 }
 
 #[cfg(test)]
@@ -37,7 +30,9 @@ mod tests {
     #[test]
     fn fam_simple() {
         assert_eq!(
-            smol::future::block_on(fast_async_map(vec![1, 2, 3, 4, 5], |x| Ok(x * 2))),
+            smol::future::block_on(
+                fast_async_stream(vec![1, 2, 3, 4, 5], |x| Ok(x * 2), 2).collect::<Vec<_>>()
+            ),
             vec![2, 4, 6, 8, 10]
         )
     }
@@ -45,9 +40,14 @@ mod tests {
     #[test]
     fn fam_blocking() {
         assert_eq!(
-            smol::future::block_on(fast_async_map(vec![1, 2, 3, 4, 5], |x| Err(smol::spawn(
-                async move { x * 2 }
-            )))),
+            smol::future::block_on(
+                fast_async_stream(
+                    vec![1, 2, 3, 4, 5],
+                    |x| Err(smol::spawn(async move { x * 2 })),
+                    2
+                )
+                .collect::<Vec<_>>()
+            ),
             vec![2, 4, 6, 8, 10]
         )
     }
@@ -0,0 +1,40 @@
+use std::{path::Path, sync::Arc};
+
+use parking_lot::Mutex;
+use rusqlite::Connection;
+
+/// A handle to melscan's own SQLite database. This holds state that melscan itself produces, such as watchlists, as opposed to the block indexer's database.
+#[derive(Clone)]
+pub struct LocalDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl LocalDb {
+    /// Opens the database at the given path, or a fresh in-memory database if no path is given.
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let conn = match path {
+            Some(path) => Connection::open(path)?,
+            None => Connection::open_in_memory()?,
+        };
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs a closure against the connection, blocking the current thread.
+    pub fn run_blocking<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        f(&mut self.conn.lock())
+    }
+
+    /// Runs a closure against the connection on a background thread.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let this = self.clone();
+        smol::unblock(move || this.run_blocking(f)).await
+    }
+}
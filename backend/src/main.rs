@@ -1,14 +1,44 @@
+#![recursion_limit = "256"]
+use apikeys::authorize;
+use cors::CorsGroup;
 use endpoints::*;
-use rweb::{hyper::Method, Filter};
+use ratelimit::rate_limit;
+use rweb::{
+    hyper::{
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn, Service},
+        Body, Request, Server,
+    },
+    Filter,
+};
+use std::convert::Infallible;
 use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
+use upstream::RemoteAddr;
 
-use crate::globals::CMD_ARGS;
+use crate::globals::{BACKEND, CONFIG, WATCHLIST};
+mod apikeys;
 mod backend;
+mod batch;
+mod blockcache;
+mod chainidx;
+mod classify;
+mod compress;
+mod config;
+mod cors;
 mod crawl;
+mod disasm;
 mod endpoints;
+mod export;
 mod globals;
+mod graphql;
 mod graphs;
+mod localdb;
+mod ratelimit;
+mod search;
+mod trace;
+mod upstream;
 mod utils;
+mod watchlist;
 
 #[macro_export]
 macro_rules! routes {
@@ -39,48 +69,112 @@ macro_rules! routes {
 #[tracing::instrument]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let log_conf = std::env::var("RUST_LOG").unwrap_or_else(|_| "melscan=debug,warn,info".into());
+    // load the configuration before anything else, so that mistakes in it are reported right away
+    let config = &*CONFIG;
+    let log_conf = std::env::var("RUST_LOG").unwrap_or_else(|_| config.log.filter.clone());
     std::env::set_var("RUST_LOG", log_conf);
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
-        .with_ansi(false)
+        .with_ansi(config.log.ansi)
         .finish()
         .init();
 
-    log::info!("listening on {}", CMD_ARGS.listen);
+    if let Some(watchlist) = WATCHLIST.as_ref() {
+        tokio::spawn(watchlist.clone().run());
+    }
+    if let Some(chainidx) = BACKEND.chain_index() {
+        smolscale::spawn(chainidx.clone().run()).detach();
+    }
 
     let routes = routes![
         overview,
         latest,
+        blocks,
         transaction,
         coins,
         block_full,
         block_summary,
         address_summary,
+        address_balances,
+        address_coins,
+        batch,
+        graphql,
+        search_any,
+        search_transactions,
+        resolve_transaction,
+        resolve_block,
         search_transaction,
         search_block,
         leaderboard,
         transaction_page,
         graph,
-        transaction_crawl
+        transaction_crawl,
+        transaction_trace,
+        watchlist_list,
+        watchlist_add,
+        watchlist_remove,
+        watchlist_deliveries,
+        covenant_disassemble,
+        covenant,
+        stakes,
+        stake_epochs,
+        staker,
+        proposer_leaderboard,
+        proposer,
+        epochs,
+        epoch,
+        admin_state,
+        admin_flush_caches,
+        admin_keys_list,
+        admin_keys_create,
+        admin_keys_revoke
     ];
-    let cors = warp::cors()
-        .allow_any_origin()
-        // .allow_credentials(true)
-        .allow_method(Method::GET)
-        .allow_method(Method::POST)
-        .allow_method(Method::OPTIONS)
-        .allow_header("content-type");
-    rweb::serve(routes.with(cors).with(warp::trace(|info| {
-        // Create a span using tracing macros
-        tracing::info_span!(
-            "request",
-            method = %info.method(),
-            path = %info.path(),
-        )
-    })))
-    .run(CMD_ARGS.listen)
-    .await;
+    let routes = rate_limit()
+        .and(authorize())
+        .and(routes)
+        .recover(handle_rejection);
+    let routes = warp::header::optional::<String>("accept-encoding")
+        .and(routes)
+        .map(compress::compress);
+    let with_cors = |cors: warp::cors::Builder| {
+        routes.clone().with(cors).with(warp::trace(|info| {
+            // Create a span using tracing macros
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = %info.path(),
+            )
+        }))
+    };
+
+    // serve through hyper directly, so that every request can be given a deadline and its group's CORS policy
+    let services = [CorsGroup::Public, CorsGroup::Admin, CorsGroup::Stream]
+        .map(|group| warp::service(with_cors(config.cors.policy(group).build())));
+    let servers = config
+        .server
+        .listen
+        .iter()
+        .map(|addr| {
+            let services = services.clone();
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let remote = RemoteAddr(conn.remote_addr());
+                let services = services.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                        req.extensions_mut().insert(remote);
+                        let path = req.uri().path().to_owned();
+                        let group = CorsGroup::of(&path, req.uri().query());
+                        let mut service = services[group as usize].clone();
+                        // hyper drops this future if the client disconnects, which cancels the request
+                        upstream::with_deadline(path, service.call(req))
+                    }))
+                }
+            });
+            log::info!("listening on {}", addr);
+            anyhow::Ok(Server::try_bind(addr)?.serve(make_service))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    futures_util::future::try_join_all(servers).await?;
 
     Ok(())
 }
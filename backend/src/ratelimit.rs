@@ -0,0 +1,235 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+use dashmap::DashMap;
+use warp::{filters::path::FullPath, reject::Reject, Filter, Rejection};
+
+use crate::{
+    apikeys::Scope,
+    globals::{API_KEYS, CONFIG, RATE_LIMITER},
+    upstream::RemoteAddr,
+};
+
+/// How many buckets are kept before full ones start being forgotten.
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+
+/// How many requests a client can make. A client can spend the whole budget in one burst, after which it refills at a steady rate over a minute. A budget of zero means no limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub per_minute: u32,
+}
+
+/// Who a request is counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    Ip(IpAddr),
+    /// The ID of an API key with the `read` scope.
+    ApiKey(u64),
+}
+
+/// Routes that can make many upstream calls get their own, stricter budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Normal,
+    Expensive,
+}
+
+impl RouteClass {
+    pub fn of(path: &str) -> Self {
+        let expensive = path.ends_with("/crawl")
+            || path.ends_with("/trace")
+            || path.starts_with("/raw/graph")
+            || path.starts_with("/raw/leaderboard/")
+            || path.starts_with("/raw/address/")
+            || path.starts_with("/raw/batch")
+            || path.starts_with("/graphql");
+        if expensive {
+            RouteClass::Expensive
+        } else {
+            RouteClass::Normal
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limits, kept separately for every client and route class.
+pub struct RateLimiter {
+    ip_budgets: [Budget; 2],
+    key_budgets: [Budget; 2],
+    buckets: DashMap<(ClientId, RouteClass), Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter, given the normal and expensive budgets for anonymous clients and for API keys.
+    pub fn new(ip_budgets: [Budget; 2], key_budgets: [Budget; 2]) -> Self {
+        Self {
+            ip_budgets,
+            key_budgets,
+            buckets: DashMap::new(),
+        }
+    }
+
+    fn budget(&self, client: &ClientId, class: RouteClass) -> Budget {
+        let budgets = match client {
+            ClientId::Ip(_) => &self.ip_budgets,
+            ClientId::ApiKey(_) => &self.key_budgets,
+        };
+        budgets[class as usize]
+    }
+
+    /// Takes a token from the client's bucket. If it is empty, returns how many seconds until it has one again.
+    pub fn check(&self, client: ClientId, class: RouteClass) -> Result<(), u64> {
+        self.check_n(client, class, 1)
+    }
+
+    /// Takes several tokens from the client's bucket at once, or none if there are not enough, returning how many seconds until there are. Taking more tokens than the bucket holds takes the whole bucket.
+    pub fn check_n(&self, client: ClientId, class: RouteClass, tokens: u32) -> Result<(), u64> {
+        let budget = self.budget(&client, class);
+        if budget.per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = budget.per_minute as f64;
+        let tokens = (tokens as f64).min(capacity);
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+        if self.buckets.len() > MAX_TRACKED_BUCKETS {
+            self.forget_full(now);
+        }
+        let mut bucket = self.buckets.entry((client, class)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            Ok(())
+        } else {
+            Err(((tokens - bucket.tokens) / per_sec).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// Forgets the buckets that would have refilled by now, which are the same as new ones.
+    fn forget_full(&self, now: Instant) {
+        self.buckets.retain(|(client, class), bucket| {
+            // buckets are only created for limited budgets, so per_minute is never zero here
+            let budget = self.budget(client, *class);
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * budget.per_minute as f64 / 60.0 < budget.per_minute as f64
+        });
+    }
+}
+
+/// Rejection for clients that ran out of requests.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: u64,
+}
+
+impl Reject for RateLimited {}
+
+/// A filter that counts every request against its client's budget, rejecting it once the budget is spent.
+pub fn rate_limit() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client()
+        .and(warp::path::full())
+        .and_then(|client: Option<ClientId>, path: FullPath| async move {
+            let client = match client {
+                Some(client) => client,
+                None => return Ok(()),
+            };
+            RATE_LIMITER
+                .check(client, RouteClass::of(path.as_str()))
+                .map_err(|retry_after| warp::reject::custom(RateLimited { retry_after }))
+        })
+        .untuple_one()
+}
+
+/// A filter that extracts who a request is counted against, for routes that charge extra tokens.
+pub fn client() -> impl Filter<Extract = (Option<ClientId>,), Error = Rejection> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .map(
+            |remote: Option<RemoteAddr>, forwarded: Option<String>, api_key: Option<String>| {
+                client_id(remote.map(|r| r.0), forwarded, api_key)
+            },
+        )
+}
+
+fn client_id(
+    remote: Option<SocketAddr>,
+    forwarded: Option<String>,
+    api_key: Option<String>,
+) -> Option<ClientId> {
+    if let Some(key) = api_key.and_then(|key| API_KEYS.lookup(&key)) {
+        if key.allows(Scope::Read) {
+            return Some(ClientId::ApiKey(key.id));
+        }
+    }
+    // behind a reverse proxy, the client is the first address it forwarded for
+    let forwarded = forwarded
+        .filter(|_| CONFIG.server.trust_forwarded_for)
+        .and_then(|f| f.split(',').next()?.trim().parse().ok());
+    forwarded
+        .or_else(|| remote.map(|addr| addr.ip()))
+        .map(ClientId::Ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_runs_out() {
+        let limiter = RateLimiter::new(
+            [Budget { per_minute: 2 }, Budget { per_minute: 0 }],
+            [Budget { per_minute: 0 }, Budget { per_minute: 0 }],
+        );
+        let client = ClientId::Ip([127, 0, 0, 1].into());
+        assert!(limiter.check(client.clone(), RouteClass::Normal).is_ok());
+        assert!(limiter.check(client.clone(), RouteClass::Normal).is_ok());
+        let retry_after = limiter
+            .check(client.clone(), RouteClass::Normal)
+            .unwrap_err();
+        assert!((1..=30).contains(&retry_after));
+        // other route classes have their own budgets
+        assert!(limiter.check(client, RouteClass::Expensive).is_ok());
+    }
+
+    #[test]
+    fn charges_several_tokens_at_once() {
+        let limiter = RateLimiter::new(
+            [Budget { per_minute: 0 }, Budget { per_minute: 10 }],
+            [Budget { per_minute: 0 }, Budget { per_minute: 0 }],
+        );
+        let client = ClientId::Ip([127, 0, 0, 1].into());
+        assert!(limiter
+            .check_n(client.clone(), RouteClass::Expensive, 8)
+            .is_ok());
+        // a charge that does not fit takes nothing
+        assert!(limiter
+            .check_n(client.clone(), RouteClass::Expensive, 3)
+            .is_err());
+        assert!(limiter
+            .check_n(client.clone(), RouteClass::Expensive, 2)
+            .is_ok());
+        assert!(limiter
+            .check(client.clone(), RouteClass::Expensive)
+            .is_err());
+
+        // more than a whole budget takes all of it
+        let other = ClientId::Ip([127, 0, 0, 2].into());
+        assert!(limiter
+            .check_n(other.clone(), RouteClass::Expensive, 500)
+            .is_ok());
+        assert!(limiter.check(other, RouteClass::Expensive).is_err());
+    }
+}
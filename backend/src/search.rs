@@ -0,0 +1,271 @@
+use std::str::FromStr;
+
+use melstructs::{Address, BlockHeight, CoinID, Denom, PoolKey, TxHash};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use tmelcrypt::HashVal;
+
+use crate::{
+    globals::{BACKEND, CLIENT, CONFIG},
+    upstream::upstream,
+};
+
+/// At most how many prefix matches of each kind are returned by a search.
+const MAX_PREFIX_MATCHES: usize = 20;
+
+/// At most how many matches are listed when resolving an ambiguous prefix.
+const MAX_AMBIGUOUS_MATCHES: usize = 100;
+
+/// The shortest hash prefix that can ever be configured. Shorter ones match so much of the chain index that resolving them amounts to a full scan.
+pub const MIN_PREFIX_LEN: usize = 4;
+
+/// Something that a search query can refer to.
+#[serde_as]
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SearchTarget {
+    Block {
+        height: BlockHeight,
+        blkhash: Option<HashVal>,
+    },
+    Transaction {
+        height: BlockHeight,
+        txhash: TxHash,
+    },
+    Coin {
+        height: BlockHeight,
+        #[serde_as(as = "DisplayFromStr")]
+        coinid: CoinID,
+    },
+    Address {
+        #[serde_as(as = "DisplayFromStr")]
+        address: Address,
+    },
+    Denom {
+        #[serde_as(as = "DisplayFromStr")]
+        denom: Denom,
+    },
+}
+
+impl SearchTarget {
+    /// Where the frontend shows this target.
+    fn url(&self) -> String {
+        match self {
+            SearchTarget::Block { height, .. } => format!("/blocks/{}", height),
+            SearchTarget::Transaction { height, txhash } => {
+                format!("/blocks/{}/{}", height, txhash)
+            }
+            SearchTarget::Coin { height, coinid } => {
+                format!("/blocks/{}/{}", height, coinid.txhash)
+            }
+            SearchTarget::Address { address } => format!("/address/{}", address),
+            SearchTarget::Denom { denom } => {
+                if *denom == Denom::Mel {
+                    "/pools/MEL/SYM".into()
+                } else {
+                    let pool = PoolKey::new(Denom::Mel, *denom);
+                    format!("/pools/{}/{}", pool.left(), pool.right())
+                }
+            }
+        }
+    }
+
+    /// Orders kinds by how likely they are to be what the user meant, all else being equal.
+    fn priority(&self) -> u8 {
+        match self {
+            SearchTarget::Transaction { .. } => 0,
+            SearchTarget::Block { .. } => 1,
+            SearchTarget::Address { .. } => 2,
+            SearchTarget::Coin { .. } => 3,
+            SearchTarget::Denom { .. } => 4,
+        }
+    }
+}
+
+/// One interpretation of a search query.
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub target: SearchTarget,
+    /// Whether the query names this target exactly, rather than being a prefix of its hash.
+    pub exact: bool,
+    pub url: String,
+}
+
+impl SearchResult {
+    fn new(target: SearchTarget, exact: bool) -> Self {
+        Self {
+            url: target.url(),
+            target,
+            exact,
+        }
+    }
+}
+
+/// Tries every interpretation of a query: block height, transaction or block hash, coin ID, address, denomination, or a prefix of a transaction or block hash. Results are ranked with exact matches first.
+pub async fn search(query: &str) -> anyhow::Result<Vec<SearchResult>> {
+    let query = query.trim();
+    let mut results = vec![];
+    if query.is_empty() {
+        return Ok(results);
+    }
+    let snap = upstream(CLIENT.latest_snapshot()).await?;
+    let tip = snap.current_header().height;
+
+    if let Ok(height) = query.parse::<u64>() {
+        if height <= tip.0 {
+            results.push(SearchResult::new(
+                SearchTarget::Block {
+                    height: BlockHeight(height),
+                    blkhash: None,
+                },
+                true,
+            ));
+        }
+    }
+
+    if let Ok(address) = Address::from_str(query) {
+        results.push(SearchResult::new(SearchTarget::Address { address }, true));
+    }
+
+    if let Ok(coinid) = CoinID::from_str(query) {
+        if let Some(height) = BACKEND.search_transaction(coinid.txhash).await? {
+            results.push(SearchResult::new(
+                SearchTarget::Coin { height, coinid },
+                true,
+            ));
+        }
+    }
+
+    if let Ok(denom) = Denom::from_str(query) {
+        let exists = match denom {
+            Denom::Mel => true,
+            Denom::NewCustom => false,
+            denom => upstream(snap.get_pool(PoolKey::new(Denom::Mel, denom)))
+                .await?
+                .is_some(),
+        };
+        if exists {
+            results.push(SearchResult::new(SearchTarget::Denom { denom }, true));
+        }
+    }
+
+    if let Ok(hash) = HashVal::from_str(query) {
+        let found = results.len();
+        if let Some(height) = BACKEND.search_transaction(TxHash(hash)).await? {
+            results.push(SearchResult::new(
+                SearchTarget::Transaction {
+                    height,
+                    txhash: TxHash(hash),
+                },
+                true,
+            ));
+        }
+        if let Some(height) = BACKEND.search_block(hash).await? {
+            results.push(SearchResult::new(
+                SearchTarget::Block {
+                    height,
+                    blkhash: Some(hash),
+                },
+                true,
+            ));
+        }
+        // any other hash could be a covenant hash, i.e. an address
+        if results.len() == found {
+            results.push(SearchResult::new(
+                SearchTarget::Address {
+                    address: Address(hash),
+                },
+                true,
+            ));
+        }
+    } else if is_hash_prefix(query) && BACKEND.chain_index().is_some() {
+        for (txhash, height) in BACKEND.find_transactions(query, MAX_PREFIX_MATCHES).await? {
+            results.push(SearchResult::new(
+                SearchTarget::Transaction { height, txhash },
+                false,
+            ));
+        }
+        for (blkhash, height) in BACKEND.find_blocks(query, MAX_PREFIX_MATCHES).await? {
+            results.push(SearchResult::new(
+                SearchTarget::Block {
+                    height,
+                    blkhash: Some(blkhash),
+                },
+                false,
+            ));
+        }
+    }
+
+    results.sort_by_key(|r| (!r.exact, r.target.priority()));
+    results.dedup_by(|a, b| a.target == b.target);
+    Ok(results)
+}
+
+/// Whether a string is long enough, and hex, to be looked up as a hash prefix.
+fn is_hash_prefix(s: &str) -> bool {
+    s.len() >= CONFIG.server.min_prefix_len
+        && s.len() <= 64
+        && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A transaction or block whose hash matched a prefix.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct HashMatch {
+    pub hash: HashVal,
+    pub height: BlockHeight,
+}
+
+/// The outcome of resolving a hash prefix.
+#[derive(Clone, Debug)]
+pub enum PrefixResolution {
+    /// The prefix is not hex, or is shorter than the configured minimum.
+    Invalid,
+    NotFound,
+    Unique(HashMatch),
+    /// More than one hash matched. Lists up to 100 of them, newest first.
+    Ambiguous(Vec<HashMatch>),
+}
+
+impl PrefixResolution {
+    fn new(mut matches: Vec<HashMatch>) -> Self {
+        match matches.len() {
+            0 => PrefixResolution::NotFound,
+            1 => PrefixResolution::Unique(matches.remove(0)),
+            _ => PrefixResolution::Ambiguous(matches),
+        }
+    }
+}
+
+/// Resolves a prefix of a transaction hash, such as the short hashes that transaction summaries display.
+pub async fn resolve_transaction_prefix(prefix: &str) -> anyhow::Result<PrefixResolution> {
+    let prefix = prefix.trim();
+    if !is_hash_prefix(prefix) {
+        return Ok(PrefixResolution::Invalid);
+    }
+    let matches = BACKEND
+        .find_transactions(prefix, MAX_AMBIGUOUS_MATCHES)
+        .await?
+        .into_iter()
+        .map(|(txhash, height)| HashMatch {
+            hash: txhash.0,
+            height,
+        })
+        .collect();
+    Ok(PrefixResolution::new(matches))
+}
+
+/// Resolves a prefix of a block hash.
+pub async fn resolve_block_prefix(prefix: &str) -> anyhow::Result<PrefixResolution> {
+    let prefix = prefix.trim();
+    if !is_hash_prefix(prefix) {
+        return Ok(PrefixResolution::Invalid);
+    }
+    let matches = BACKEND
+        .find_blocks(prefix, MAX_AMBIGUOUS_MATCHES)
+        .await?
+        .into_iter()
+        .map(|(hash, height)| HashMatch { hash, height })
+        .collect();
+    Ok(PrefixResolution::new(matches))
+}
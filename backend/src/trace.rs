@@ -0,0 +1,568 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use futures_util::future::try_join_all;
+use melstructs::{Address, BlockHeight, CoinDataHeight, CoinID, Header, Transaction, TxHash};
+use melvm::{opcode::OpCode, Covenant, CovenantEnv, Value};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    disasm::{decode_ops, disassemble, Op},
+    globals::CLIENT,
+    upstream::upstream,
+};
+
+/// Executions running longer than this are cut off.
+const MAX_TRACE_STEPS: usize = 10000;
+
+/// Traces are cut off once the stacks and heap writes they record add up to this many bytes and values.
+const MAX_TRACE_SIZE: usize = 4 << 20;
+
+/// A MelVM value, in a JSON-friendly form.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum TraceValue {
+    /// A 256-bit integer, in decimal.
+    Int(String),
+    /// A byte string, in hex.
+    Bytes(String),
+    Vector(Vec<TraceValue>),
+}
+
+impl TraceValue {
+    fn new(value: &Value) -> Self {
+        match value {
+            Value::Int(n) => TraceValue::Int(n.to_string()),
+            Value::Bytes(b) => TraceValue::Bytes(hex::encode(Vec::from(b.clone()))),
+            Value::Vector(v) => {
+                TraceValue::Vector(Vec::from(v.clone()).iter().map(TraceValue::new).collect())
+            }
+        }
+    }
+
+    /// Roughly how much room this value takes up in a response.
+    fn size(&self) -> usize {
+        match self {
+            TraceValue::Int(n) => n.len(),
+            TraceValue::Bytes(b) => b.len(),
+            TraceValue::Vector(v) => 1 + v.iter().map(TraceValue::size).sum::<usize>(),
+        }
+    }
+}
+
+/// The state of the VM right after executing one instruction.
+#[derive(Serialize, Clone, Debug)]
+pub struct TraceStep {
+    /// Index of the instruction that was executed.
+    pub pc: usize,
+    pub op: Option<Op>,
+    /// The whole stack, bottom first.
+    pub stack: Vec<TraceValue>,
+    /// Heap slots that this instruction changed.
+    pub heap_writes: BTreeMap<u16, TraceValue>,
+}
+
+/// The execution trace of the covenant unlocking one input.
+#[serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct InputTrace {
+    pub index: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    pub coinid: CoinID,
+    pub coin: CoinDataHeight,
+    #[serde_as(as = "DisplayFromStr")]
+    pub covhash: Address,
+    /// Whether the covenant could be run at all. It can't when the transaction doesn't reveal it, in which case there are no steps.
+    pub traced: bool,
+    /// The heap before execution, as set up from the transaction and environment.
+    pub initial_heap: BTreeMap<u16, TraceValue>,
+    pub steps: Vec<TraceStep>,
+    /// Why execution stopped early, if it did.
+    pub error: Option<String>,
+    /// Whether the covenant accepted the transaction.
+    pub passed: bool,
+}
+
+/// Re-runs the covenant of every input of a confirmed transaction, in the environment it was originally checked in. Returns None if the transaction is not at this height.
+pub async fn trace_transaction(
+    height: BlockHeight,
+    txhash: TxHash,
+) -> anyhow::Result<Option<Vec<InputTrace>>> {
+    let snap = upstream(CLIENT.snapshot(height)).await?;
+    let transaction = match upstream(snap.get_transaction(txhash)).await? {
+        Some(tx) => tx,
+        None => return Ok(None),
+    };
+    let coins = try_join_all(transaction.inputs.iter().map(|cid| {
+        let snap = snap.clone();
+        let cid = *cid;
+        async move {
+            anyhow::Ok(
+                upstream(snap.get_coin_spent_here(cid))
+                    .await?
+                    .context("spent coin not found")?,
+            )
+        }
+    }))
+    .await?;
+    // covenants are checked against the state the transaction was applied to, i.e. the previous block
+    let last_header = upstream(snap.get_older(height.0.saturating_sub(1).into()))
+        .await?
+        .current_header();
+    let traces = smol::unblock(move || {
+        transaction
+            .inputs
+            .iter()
+            .zip(coins.iter())
+            .enumerate()
+            .map(|(index, (coinid, cdh))| {
+                trace_input(&transaction, &last_header, index, *coinid, cdh)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await?;
+    Ok(Some(traces))
+}
+
+fn trace_input(
+    transaction: &Transaction,
+    last_header: &Header,
+    index: usize,
+    coinid: CoinID,
+    cdh: &CoinDataHeight,
+) -> anyhow::Result<InputTrace> {
+    let covhash = cdh.coin_data.covhash;
+    let covenant = match transaction
+        .covenants
+        .iter()
+        .find(|cov| tmelcrypt::hash_single(cov) == covhash.0)
+    {
+        Some(covenant) => covenant,
+        None => {
+            return Ok(InputTrace {
+                index,
+                coinid,
+                coin: cdh.clone(),
+                covhash,
+                traced: false,
+                initial_heap: BTreeMap::new(),
+                steps: vec![],
+                error: Some("the transaction does not reveal this input's covenant".into()),
+                passed: false,
+            })
+        }
+    };
+    let ops = disassemble(covenant)?.ops;
+    let opcodes = decode_ops(covenant)?;
+    let env = CovenantEnv {
+        parent_coinid: coinid,
+        parent_cdh: cdh.clone(),
+        spender_index: index as u8,
+        last_header: *last_header,
+    };
+    // the verdict comes from melvm itself, whatever the stepper below makes of the covenant
+    let passed = Covenant::from_ops(&opcodes)
+        .execute(transaction, Some(env.clone()))
+        .map(Value::into_bool)
+        .unwrap_or(false);
+    let mut stepper = Stepper::new(opcodes, initial_heap(transaction, &env));
+
+    let initial_heap: BTreeMap<u16, TraceValue> = stepper
+        .heap
+        .iter()
+        .map(|(k, v)| (*k, TraceValue::new(v)))
+        .collect();
+    let mut size = initial_heap.values().map(TraceValue::size).sum::<usize>();
+    let mut steps = vec![];
+    let mut error = None;
+    while !stepper.at_end() {
+        if steps.len() >= MAX_TRACE_STEPS {
+            error = Some(format!("stopped after {} steps", MAX_TRACE_STEPS));
+            break;
+        }
+        if size >= MAX_TRACE_SIZE {
+            error = Some(format!(
+                "stopped after {} steps, the trace is too large",
+                steps.len()
+            ));
+            break;
+        }
+        let pc = stepper.pc;
+        let written = match stepper.step() {
+            Some(written) => written,
+            None => {
+                error = Some(format!("execution failed at instruction {}", pc));
+                break;
+            }
+        };
+        // only the slot a store wrote is rendered, not the whole heap
+        let heap_writes: BTreeMap<u16, TraceValue> = written
+            .and_then(|slot| Some((slot, TraceValue::new(stepper.heap.get(&slot)?))))
+            .into_iter()
+            .collect();
+        let stack: Vec<TraceValue> = stepper.stack.iter().map(TraceValue::new).collect();
+        size += stack
+            .iter()
+            .chain(heap_writes.values())
+            .map(TraceValue::size)
+            .sum::<usize>();
+        steps.push(TraceStep {
+            pc,
+            op: ops.get(pc).cloned(),
+            stack,
+            heap_writes,
+        });
+    }
+    Ok(InputTrace {
+        index,
+        coinid,
+        coin: cdh.clone(),
+        covhash,
+        traced: true,
+        initial_heap,
+        steps,
+        error,
+        passed,
+    })
+}
+
+/// The heap a covenant starts with, laid out the way melvm lays it out.
+fn initial_heap(transaction: &Transaction, env: &CovenantEnv) -> HashMap<u16, Value> {
+    let coin = &env.parent_cdh.coin_data;
+    vec![
+        (0, Value::from(transaction.clone())),
+        (1, Value::from(transaction.hash_nosigs().0)),
+        (2, Value::from(env.parent_coinid.txhash.0)),
+        (3, Value::from(env.parent_coinid.index as u64)),
+        (4, Value::from(coin.covhash.0)),
+        (5, Value::from(coin.value.0)),
+        (6, Value::from(coin.denom)),
+        (7, Value::from(coin.additional_data.clone())),
+        (8, Value::from(env.parent_cdh.height.0)),
+        (9, Value::from(env.spender_index as u64)),
+        (10, Value::from(env.last_header)),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// Runs MelVM code one instruction at a time. melvm doesn't expose its own executor, so control flow, the heap and literals are handled here, and every other instruction is run by melvm on just its operands.
+struct Stepper {
+    stack: Vec<Value>,
+    heap: HashMap<u16, Value>,
+    ops: Vec<OpCode>,
+    pc: usize,
+    /// Loops being executed, innermost last, as (first instruction, last instruction, iterations left).
+    loops: Vec<(usize, usize, u16)>,
+}
+
+impl Stepper {
+    fn new(ops: Vec<OpCode>, heap: HashMap<u16, Value>) -> Self {
+        Self {
+            stack: vec![],
+            heap,
+            ops,
+            pc: 0,
+            loops: vec![],
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+
+    /// Executes one instruction, returning the heap slot it wrote if any, or None if it failed.
+    fn step(&mut self) -> Option<Option<u16>> {
+        let result = self.execute();
+        // like melvm, loops wind back even after a failed instruction
+        while let Some((begin, end, iterations_left)) = self.loops.pop() {
+            if self.pc <= end {
+                self.loops.push((begin, end, iterations_left));
+                break;
+            }
+            if iterations_left > 0 && self.pc - end == 1 {
+                self.loops.push((begin, end, iterations_left - 1));
+                self.pc = begin;
+                break;
+            }
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Option<Option<u16>> {
+        let op = self.ops.get(self.pc)?.clone();
+        self.pc += 1;
+        match op {
+            OpCode::Noop => {}
+            OpCode::Store => {
+                let slot = self.stack.pop()?.into_u16()?;
+                let value = self.stack.pop()?;
+                self.heap.insert(slot, value);
+                return Some(Some(slot));
+            }
+            OpCode::StoreImm(slot) => {
+                let value = self.stack.pop()?;
+                self.heap.insert(slot, value);
+                return Some(Some(slot));
+            }
+            OpCode::Load => {
+                let slot = self.stack.pop()?.into_u16()?;
+                self.stack.push(self.heap.get(&slot)?.clone());
+            }
+            OpCode::LoadImm(slot) => self.stack.push(self.heap.get(&slot)?.clone()),
+            OpCode::Bez(gap) => {
+                if self.stack.pop()?.into_int() == Some(0u32.into()) {
+                    self.pc += gap as usize;
+                }
+            }
+            OpCode::Bnz(gap) => {
+                if self.stack.pop()?.into_int() != Some(0u32.into()) {
+                    self.pc += gap as usize;
+                }
+            }
+            OpCode::Jmp(gap) => self.pc += gap as usize,
+            OpCode::Loop(iterations, count) => {
+                if iterations == 0 {
+                    self.pc += count as usize;
+                } else {
+                    let end = (self.pc + count as usize).checked_sub(1)?;
+                    // loops must nest
+                    if matches!(self.loops.last(), Some((_, outer_end, _)) if end > *outer_end) {
+                        return None;
+                    }
+                    self.loops.push((self.pc, end, iterations - 1));
+                }
+            }
+            OpCode::PushB(bytes) => self.stack.push(Value::from_bytes(&bytes)),
+            OpCode::PushI(n) | OpCode::PushIC(n) => self.stack.push(Value::Int(n)),
+            OpCode::Dup => {
+                let value = self.stack.pop()?;
+                self.stack.push(value.clone());
+                self.stack.push(value);
+            }
+            op => {
+                // the remaining instructions pop a fixed number of operands and push one result
+                let operands = self
+                    .stack
+                    .split_off(self.stack.len().checked_sub(arity(&op)?)?);
+                let mut program: Vec<OpCode> =
+                    (0..operands.len() as u16).map(OpCode::LoadImm).collect();
+                program.push(op);
+                let result = Covenant::from_ops(&program).debug_execute(&operands)?;
+                self.stack.push(result);
+            }
+        }
+        Some(None)
+    }
+}
+
+/// How many operands an instruction that Stepper leaves to melvm takes off the stack.
+fn arity(op: &OpCode) -> Option<usize> {
+    Some(match op {
+        OpCode::VEmpty | OpCode::BEmpty => 0,
+        OpCode::Not
+        | OpCode::Hash(_)
+        | OpCode::VLength
+        | OpCode::BLength
+        | OpCode::ItoB
+        | OpCode::BtoI
+        | OpCode::TypeQ => 1,
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Rem
+        | OpCode::Exp(_)
+        | OpCode::And
+        | OpCode::Or
+        | OpCode::Xor
+        | OpCode::Eql
+        | OpCode::Lt
+        | OpCode::Gt
+        | OpCode::Shl
+        | OpCode::Shr
+        | OpCode::VRef
+        | OpCode::VAppend
+        | OpCode::VPush
+        | OpCode::VCons
+        | OpCode::BRef
+        | OpCode::BAppend
+        | OpCode::BPush
+        | OpCode::BCons => 2,
+        OpCode::SigEOk(_) | OpCode::VSet | OpCode::VSlice | OpCode::BSet | OpCode::BSlice => 3,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use melstructs::{CoinData, CoinValue, Denom, NetID};
+    use tmelcrypt::HashVal;
+
+    use super::*;
+
+    fn spend(covenant: &Covenant, reveal: bool) -> (Transaction, CoinDataHeight) {
+        let mut transaction = Transaction::default();
+        if reveal {
+            transaction.covenants.push(covenant.to_bytes());
+        }
+        let cdh = CoinDataHeight {
+            coin_data: CoinData {
+                covhash: covenant.hash(),
+                value: CoinValue(1),
+                denom: Denom::Mel,
+                additional_data: Default::default(),
+            },
+            height: BlockHeight(1),
+        };
+        (transaction, cdh)
+    }
+
+    fn header() -> Header {
+        Header {
+            network: NetID::Testnet,
+            previous: HashVal::default(),
+            height: BlockHeight(1),
+            history_hash: HashVal::default(),
+            coins_hash: HashVal::default(),
+            transactions_hash: HashVal::default(),
+            fee_pool: CoinValue(0),
+            fee_multiplier: 1,
+            dosc_speed: 1,
+            pools_hash: HashVal::default(),
+            stakes_hash: HashVal::default(),
+        }
+    }
+
+    fn coinid() -> CoinID {
+        CoinID {
+            txhash: TxHash(HashVal::default()),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn records_steps_and_heap_writes() {
+        let covenant = Covenant::from_ops(&[
+            OpCode::PushI(5u32.into()),
+            OpCode::StoreImm(20),
+            OpCode::PushI(7u32.into()),
+            OpCode::PushI(21u32.into()),
+            OpCode::Store,
+            OpCode::LoadImm(20),
+        ]);
+        let (transaction, cdh) = spend(&covenant, true);
+        let trace = trace_input(&transaction, &header(), 0, coinid(), &cdh).unwrap();
+        assert!(trace.traced);
+        assert!(trace.passed);
+        assert_eq!(trace.error, None);
+        assert_eq!(trace.steps.len(), 6);
+        assert_eq!(
+            trace.initial_heap.get(&9),
+            Some(&TraceValue::Int("0".into()))
+        );
+
+        let writes: Vec<_> = trace
+            .steps
+            .iter()
+            .map(|step| step.heap_writes.clone())
+            .collect();
+        assert_eq!(
+            writes[1],
+            BTreeMap::from([(20, TraceValue::Int("5".into()))])
+        );
+        assert_eq!(
+            writes[4],
+            BTreeMap::from([(21, TraceValue::Int("7".into()))])
+        );
+        // nothing else touches the heap
+        assert_eq!(writes.iter().filter(|w| !w.is_empty()).count(), 2);
+        assert_eq!(trace.steps[5].stack, vec![TraceValue::Int("5".into())]);
+    }
+
+    #[test]
+    fn unrevealed_covenants_are_untraceable() {
+        let (transaction, cdh) = spend(&Covenant::always_true(), false);
+        let trace = trace_input(&transaction, &header(), 0, coinid(), &cdh).unwrap();
+        assert!(!trace.traced);
+        assert!(!trace.passed);
+        assert!(trace.steps.is_empty());
+        assert!(trace.error.is_some());
+    }
+
+    #[test]
+    fn steps_agree_with_melvm() {
+        let programs = vec![
+            // a loop adding one to a heap slot three times, then a branch on the result
+            vec![
+                OpCode::PushI(0u32.into()),
+                OpCode::StoreImm(20),
+                OpCode::Loop(3, 4),
+                OpCode::LoadImm(20),
+                OpCode::PushI(1u32.into()),
+                OpCode::Add,
+                OpCode::StoreImm(20),
+                OpCode::PushI(3u32.into()),
+                OpCode::LoadImm(20),
+                OpCode::Eql,
+                OpCode::Bnz(1),
+                OpCode::PushI(0u32.into()),
+                OpCode::PushB(b"done".to_vec()),
+                OpCode::BLength,
+            ],
+            // operands reach melvm in the right order
+            vec![
+                OpCode::PushI(0u32.into()),
+                OpCode::PushI(9u32.into()),
+                OpCode::VEmpty,
+                OpCode::VPush,
+                OpCode::VRef,
+            ],
+            // and failures fail
+            vec![OpCode::PushI(5u32.into()), OpCode::VEmpty, OpCode::VRef],
+        ];
+        for ops in programs {
+            let expected = Covenant::from_ops(&ops).debug_execute(&[]);
+            let mut stepper = Stepper::new(ops, HashMap::new());
+            let mut failed = false;
+            while !stepper.at_end() {
+                if stepper.step().is_none() {
+                    failed = true;
+                    break;
+                }
+            }
+            let actual = if failed { None } else { stepper.stack.pop() };
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn traces_signature_checks() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let covenant = Covenant::std_ed25519_pk_new(sk.to_public());
+        let (transaction, cdh) = spend(&covenant, true);
+        let transaction = transaction.signed_ed25519(sk);
+        let trace = trace_input(&transaction, &header(), 0, coinid(), &cdh).unwrap();
+        assert!(trace.passed);
+        assert_eq!(trace.error, None);
+        assert_eq!(trace.steps.len(), 8);
+        assert_eq!(
+            trace.steps.last().unwrap().stack,
+            vec![TraceValue::Int("1".into())]
+        );
+
+        // the wrong key fails, and the trace shows why
+        let other = tmelcrypt::Ed25519SK::generate().to_public();
+        let covenant = Covenant::std_ed25519_pk_new(other);
+        let (mut transaction, cdh) = spend(&covenant, true);
+        transaction.sigs = vec![];
+        let transaction = transaction.signed_ed25519(sk);
+        let trace = trace_input(&transaction, &header(), 0, coinid(), &cdh).unwrap();
+        assert!(!trace.passed);
+        assert_eq!(
+            trace.steps.last().unwrap().stack,
+            vec![TraceValue::Int("0".into())]
+        );
+    }
+}
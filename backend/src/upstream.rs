@@ -0,0 +1,113 @@
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use futures_util::Future;
+use once_cell::sync::Lazy;
+use smol::lock::Semaphore;
+use warp::hyper::{Body, Response, StatusCode};
+
+use crate::{globals::CONFIG, ratelimit::RouteClass};
+
+/// Permits for calls to the full node, shared by every request. Routes that fan out take a permit for each call, so one expensive request cannot crowd out everything else. A permit must never be held while waiting for another one.
+static UPSTREAM_PERMITS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(CONFIG.upstream.concurrency.max(1)));
+
+/// The address a connection came from, stored in the extensions of every request on it.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Runs a call to the full node once the global budget has room for it.
+pub async fn upstream<T>(call: impl Future<Output = T>) -> T {
+    with_permit(&UPSTREAM_PERMITS, call).await
+}
+
+async fn with_permit<T>(permits: &Semaphore, call: impl Future<Output = T>) -> T {
+    let _permit = permits.acquire().await;
+    call.await
+}
+
+/// How long a request to the given path can take.
+pub fn deadline(path: &str) -> Duration {
+    let secs = match RouteClass::of(path) {
+        RouteClass::Normal => CONFIG.server.request_timeout,
+        RouteClass::Expensive => CONFIG.server.expensive_request_timeout,
+    };
+    Duration::from_secs(secs)
+}
+
+/// Serves a request, replacing its response with a `504 Gateway Timeout` if it takes longer than its route's deadline. Whatever the request was still doing is cancelled.
+pub async fn with_deadline(
+    path: String,
+    response: impl Future<Output = Result<Response<Body>, Infallible>>,
+) -> Result<Response<Body>, Infallible> {
+    within(deadline(&path), &path, response).await
+}
+
+async fn within(
+    deadline: Duration,
+    path: &str,
+    response: impl Future<Output = Result<Response<Body>, Infallible>>,
+) -> Result<Response<Body>, Infallible> {
+    match tokio::time::timeout(deadline, response).await {
+        Ok(response) => response,
+        Err(_) => {
+            log::warn!("{} timed out after {:?}", path, deadline);
+            let body = serde_json::json!({ "error": "request timed out" });
+            let mut response = Response::new(Body::from(body.to_string()));
+            *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+            response.headers_mut().insert(
+                warp::hyper::header::CONTENT_TYPE,
+                warp::hyper::header::HeaderValue::from_static("application/json"),
+            );
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future::join_all;
+
+    use super::*;
+
+    #[test]
+    fn slow_requests_time_out() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let slow = within(Duration::from_millis(10), "/raw/latest", async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(Response::new(Body::empty()))
+            })
+            .await
+            .unwrap();
+            assert_eq!(slow.status(), StatusCode::GATEWAY_TIMEOUT);
+
+            let fast = within(Duration::from_secs(60), "/raw/latest", async {
+                Ok(Response::new(Body::empty()))
+            })
+            .await
+            .unwrap();
+            assert_eq!(fast.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn permits_bound_concurrency() {
+        let permits = Semaphore::new(2);
+        let in_flight = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        smol::block_on(join_all((0..8).map(|_| {
+            with_permit(&permits, async {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                smol::Timer::after(Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        })));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::backend::TransactionSummary;
+use crate::{
+    backend::TransactionSummary,
+    globals::{BLOCK_CACHE, CONFIG},
+    upstream::upstream,
+};
 use anyhow::Context;
 use futures_util::{stream::FuturesOrdered, Future};
 use melprot::Snapshot;
@@ -12,24 +16,51 @@ pub fn get_old_blocks(
     last_snap: &Snapshot,
     depth: usize,
 ) -> FuturesOrdered<impl Future<Output = anyhow::Result<(Block, CoinValue)>>> {
-    static CACHE: Lazy<Cache<u64, (Block, CoinValue)>> = Lazy::new(|| Cache::new(100));
+    get_blocks(
+        last_snap,
+        (0..=last_snap.current_header().height.0).rev().take(depth),
+    )
+}
+
+/// Recently fetched blocks and their proposer rewards, kept in memory.
+pub static BLOCK_MEMORY_CACHE: Lazy<Cache<u64, (Block, CoinValue)>> =
+    Lazy::new(|| Cache::new(CONFIG.cache.block_capacity));
+
+/// Concurrently fetches the given blocks along with their proposer rewards, yielding them in the same order as the heights. Blocks are served from the durable block cache when possible.
+pub fn get_blocks(
+    last_snap: &Snapshot,
+    heights: impl IntoIterator<Item = u64>,
+) -> FuturesOrdered<impl Future<Output = anyhow::Result<(Block, CoinValue)>>> {
     let mut futs = FuturesOrdered::new();
-    for height in (0..=last_snap.current_header().height.0).rev().take(depth) {
+    for height in heights {
         let last_snap = last_snap.clone();
-        futs.push(async move {
-            if let Some(res) = CACHE.get(&height) {
-                Ok(res)
-            } else {
-                // log::debug!("rendering block {}", height);
+        futs.push_back(async move {
+            if let Some(res) = BLOCK_MEMORY_CACHE.get(&height) {
+                return Ok(res);
+            }
+            if let Some(block_cache) = BLOCK_CACHE.as_ref() {
+                if let Some(res) = block_cache.get(height.into()).await? {
+                    BLOCK_MEMORY_CACHE.insert(height, res.clone());
+                    return Ok(res);
+                }
+            }
+            // log::debug!("rendering block {}", height);
+            let (old_block, reward_amount) = upstream(async {
                 let old_snap = last_snap.get_older(height.into()).await?;
                 let reward_coin = old_snap
                     .get_coin(CoinID::proposer_reward(height.into()))
                     .await?;
                 let reward_amount = reward_coin.map(|v| v.coin_data.value).unwrap_or_default();
-                let old_block = old_snap.current_block().await?;
-                CACHE.insert(height, (old_block.clone(), reward_amount));
-                Ok((old_block, reward_amount))
+                anyhow::Ok((old_snap.current_block().await?, reward_amount))
+            })
+            .await?;
+            BLOCK_MEMORY_CACHE.insert(height, (old_block.clone(), reward_amount));
+            if let Some(block_cache) = BLOCK_CACHE.as_ref() {
+                if let Err(err) = block_cache.insert(&old_block, reward_amount).await {
+                    log::warn!("could not cache block {}: {:?}", height, err);
+                }
             }
+            Ok((old_block, reward_amount))
         });
     }
     futs
@@ -39,7 +70,7 @@ pub fn get_transactions(block: &Block) -> Vec<TransactionSummary> {
     let mut transactions: Vec<TransactionSummary> = Vec::new();
     for transaction in &block.transactions {
         transactions.push(TransactionSummary {
-            hash: hex::encode(&transaction.hash_nosigs().0),
+            hash: hex::encode(transaction.hash_nosigs().0),
             shorthash: hex::encode(&transaction.hash_nosigs().0[0..5]),
             height: block.header.height.0,
             weight: transaction.weight(covenant_weight_from_bytes),
@@ -0,0 +1,906 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{TimeZone, Utc};
+use futures_util::{future::try_join_all, stream, TryStreamExt};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use melprot::Snapshot;
+use melstructs::{Address, Block, BlockHeight, CoinData, CoinID, CoinValue, Denom, TxHash};
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension};
+use rweb::hyper::{
+    client::{connect::dns::Name, HttpConnector},
+    service::Service,
+    Body, Client, Request, Uri,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{globals::CLIENT, graphs::height_to_datetime, localdb::LocalDb, upstream::upstream};
+
+/// How often the watcher polls for new blocks and pending deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a single webhook request may take before it counts as a failed attempt.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many webhook requests may be in flight at once.
+const DELIVERY_CONCURRENCY: usize = 16;
+
+/// How many times scanning a block is attempted before the block is skipped, so that one block that cannot be scanned does not stall the watcher for good.
+const MAX_SCAN_ATTEMPTS: u32 = 30;
+
+/// How many times a delivery is attempted before it is given up on.
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Base delay between retries, doubled after every failed attempt.
+const RETRY_BASE_SECS: i64 = 15;
+
+/// Longest delay between two retries.
+const RETRY_MAX_SECS: i64 = 3600;
+
+/// A rule matching transactions that touch certain coins. All the fields that are set must match the *same* coin, which may be either an input or an output of the transaction.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WatchRule {
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub address: Option<Address>,
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub denom: Option<Denom>,
+    /// Minimum coin value, in micro-units.
+    #[serde(default)]
+    pub min_value: Option<CoinValue>,
+    /// Free-form label, passed through to every event.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Where to deliver events. Falls back to the globally configured webhook.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+impl WatchRule {
+    fn matches(&self, coin: &CoinData) -> bool {
+        self.address.map(|a| a == coin.covhash).unwrap_or(true)
+            && self.denom.map(|d| d == coin.denom).unwrap_or(true)
+            && self.min_value.map(|v| coin.value >= v).unwrap_or(true)
+    }
+}
+
+/// A registered watch.
+#[derive(Serialize, Clone, Debug)]
+pub struct Watch {
+    pub id: u64,
+    pub rule: WatchRule,
+    pub created: chrono::DateTime<Utc>,
+}
+
+/// The JSON body POSTed to a webhook when a transaction matches a watch.
+#[derive(Serialize, Clone, Debug)]
+pub struct WatchEvent {
+    pub watch_id: u64,
+    pub label: Option<String>,
+    pub height: BlockHeight,
+    pub date: chrono::DateTime<Utc>,
+    pub txhash: TxHash,
+    pub kind: String,
+    pub matches: Vec<WatchMatch>,
+}
+
+/// A single coin that caused a watch to match.
+#[serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct WatchMatch {
+    pub direction: CoinDirection,
+    #[serde_as(as = "DisplayFromStr")]
+    pub coinid: CoinID,
+    #[serde_as(as = "DisplayFromStr")]
+    pub covhash: Address,
+    #[serde_as(as = "DisplayFromStr")]
+    pub denom: Denom,
+    pub value: CoinValue,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoinDirection {
+    Input,
+    Output,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+/// An entry in the delivery log.
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub id: u64,
+    pub watch_id: u64,
+    pub height: BlockHeight,
+    pub txhash: TxHash,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created: chrono::DateTime<Utc>,
+    pub payload: serde_json::Value,
+}
+
+/// The watchlist subsystem. Watches, the delivery log, and how far the chain has been scanned are all persisted in the local database, so that pending deliveries survive restarts.
+#[derive(Clone)]
+pub struct Watchlist {
+    db: LocalDb,
+    default_webhook: Option<String>,
+    /// Client for the default webhook, which the operator configured and may well be internal.
+    http: Client<HttpsConnector<HttpConnector>>,
+    /// Client for webhooks given by API users, which refuses to connect to internal addresses.
+    public_http: Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+    /// The height that last failed to scan, and how many times in a row it did.
+    scan_failures: Arc<Mutex<(u64, u32)>>,
+}
+
+impl Watchlist {
+    /// Creates a watchlist backed by the given database, creating the tables if needed.
+    pub fn new(db: LocalDb, default_webhook: Option<String>) -> anyhow::Result<Self> {
+        db.run_blocking(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS watches (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    rule TEXT NOT NULL,
+                    created INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS watch_deliveries (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    watch_id INTEGER NOT NULL,
+                    height INTEGER NOT NULL,
+                    txhash TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    payload TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    next_attempt INTEGER NOT NULL,
+                    last_error TEXT,
+                    created INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS watch_deliveries_pending ON watch_deliveries (status, next_attempt);
+                DELETE FROM watch_deliveries WHERE id NOT IN (
+                    SELECT MIN(id) FROM watch_deliveries GROUP BY watch_id, txhash
+                );
+                CREATE UNIQUE INDEX IF NOT EXISTS watch_deliveries_unique ON watch_deliveries (watch_id, txhash);
+                CREATE TABLE IF NOT EXISTS watch_cursor (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    height INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS watch_skipped_blocks (
+                    height INTEGER PRIMARY KEY,
+                    error TEXT NOT NULL,
+                    skipped INTEGER NOT NULL
+                );",
+            )?;
+            Ok(())
+        })?;
+        Ok(Self {
+            db,
+            default_webhook,
+            http: Client::builder().build(with_tls(HttpConnector::new())),
+            public_http: Client::builder()
+                .build(with_tls(HttpConnector::new_with_resolver(PublicResolver))),
+            scan_failures: Default::default(),
+        })
+    }
+
+    /// Registers a new watch.
+    pub async fn add_watch(&self, rule: WatchRule) -> anyhow::Result<Watch> {
+        if rule.address.is_none() && rule.denom.is_none() && rule.min_value.is_none() {
+            anyhow::bail!("a watch needs at least one of address, denom or min_value")
+        }
+        let url = rule
+            .webhook
+            .as_ref()
+            .or(self.default_webhook.as_ref())
+            .context("no webhook given and no default webhook configured")?;
+        let uri: Uri = url.parse().context("invalid webhook URL")?;
+        if !matches!(uri.scheme_str(), Some("http") | Some("https")) {
+            anyhow::bail!("webhooks must be http:// or https:// URLs")
+        }
+        if rule.webhook.is_some() {
+            check_public_webhook(url).await?;
+        }
+        let created = Utc::now();
+        let rule_json = serde_json::to_string(&rule)?;
+        let id = self
+            .db
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO watches (rule, created) VALUES (?1, ?2)",
+                    params![rule_json, created.timestamp()],
+                )?;
+                Ok(conn.last_insert_rowid() as u64)
+            })
+            .await?;
+        Ok(Watch { id, rule, created })
+    }
+
+    /// Removes a watch, returning whether it existed. Its delivery log is kept.
+    pub async fn remove_watch(&self, id: u64) -> anyhow::Result<bool> {
+        self.db
+            .run(move |conn| {
+                let n = conn.execute("DELETE FROM watches WHERE id = ?1", params![id as i64])?;
+                Ok(n > 0)
+            })
+            .await
+    }
+
+    /// Lists all registered watches.
+    pub async fn list_watches(&self) -> anyhow::Result<Vec<Watch>> {
+        self.db
+            .run(|conn| {
+                let mut stmt = conn.prepare("SELECT id, rule, created FROM watches ORDER BY id")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                })?;
+                let mut watches = vec![];
+                for row in rows {
+                    let (id, rule, created) = row?;
+                    watches.push(Watch {
+                        id: id as u64,
+                        rule: serde_json::from_str(&rule)?,
+                        created: unix_to_datetime(created),
+                    });
+                }
+                Ok(watches)
+            })
+            .await
+    }
+
+    /// Lists the most recent deliveries, optionally only those of one watch.
+    pub async fn list_deliveries(
+        &self,
+        watch_id: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Delivery>> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, watch_id, height, txhash, url, status, attempts, last_error, created, payload
+                    FROM watch_deliveries
+                    WHERE (?1 IS NULL OR watch_id = ?1)
+                    ORDER BY id DESC LIMIT ?2",
+                )?;
+                let rows = stmt.query_map(
+                    params![watch_id.map(|id| id as i64), limit as i64],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, i64>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, String>(4)?,
+                            row.get::<_, String>(5)?,
+                            row.get::<_, i64>(6)?,
+                            row.get::<_, Option<String>>(7)?,
+                            row.get::<_, i64>(8)?,
+                            row.get::<_, String>(9)?,
+                        ))
+                    },
+                )?;
+                let mut deliveries = vec![];
+                for row in rows {
+                    let (id, watch_id, height, txhash, url, status, attempts, last_error, created, payload) =
+                        row?;
+                    deliveries.push(Delivery {
+                        id: id as u64,
+                        watch_id: watch_id as u64,
+                        height: BlockHeight(height as u64),
+                        txhash: txhash.parse()?,
+                        url,
+                        status: DeliveryStatus::parse(&status),
+                        attempts: attempts as u32,
+                        last_error,
+                        created: unix_to_datetime(created),
+                        payload: serde_json::from_str(&payload)?,
+                    });
+                }
+                Ok(deliveries)
+            })
+            .await
+    }
+
+    /// Runs the watcher forever, scanning new blocks for matches and delivering events.
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.scan_new_blocks().await {
+                log::warn!("watchlist failed to scan new blocks: {:?}", err);
+            }
+            if let Err(err) = self.deliver_pending().await {
+                log::warn!("watchlist failed to deliver events: {:?}", err);
+            }
+            smol::Timer::after(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn scan_new_blocks(&self) -> anyhow::Result<()> {
+        let snap = upstream(CLIENT.latest_snapshot()).await?;
+        let tip = snap.current_header().height;
+        let cursor = self
+            .db
+            .run(|conn| {
+                Ok(conn
+                    .query_row("SELECT height FROM watch_cursor WHERE id = 0", [], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .optional()?)
+            })
+            .await?;
+        // on the very first run, we only watch blocks from now on
+        let start = cursor.map(|h| h as u64 + 1).unwrap_or(tip.0);
+        let watches = self.list_watches().await?;
+        for height in start..=tip.0 {
+            let (deliveries, skipped) = if watches.is_empty() {
+                (vec![], None)
+            } else {
+                let scanned = async {
+                    let old_snap = upstream(snap.get_older(height.into())).await?;
+                    let block = upstream(old_snap.current_block()).await?;
+                    self.scan_block(&old_snap, block, &watches).await
+                }
+                .await;
+                match scanned {
+                    Ok(deliveries) => (deliveries, None),
+                    Err(err) => {
+                        let attempts = self.record_scan_failure(height);
+                        if attempts < MAX_SCAN_ATTEMPTS {
+                            return Err(err.context(format!("cannot scan block {}", height)));
+                        }
+                        log::error!(
+                            "watchlist skipping block {} after {} failed scans: {:?}",
+                            height,
+                            attempts,
+                            err
+                        );
+                        (vec![], Some(format!("{:#}", err)))
+                    }
+                }
+            };
+            // a block's deliveries and the cursor move together, so a failed scan never queues a webhook twice
+            self.db
+                .run(move |conn| {
+                    let tx = conn.transaction()?;
+                    let now = Utc::now().timestamp();
+                    if let Some(error) = skipped {
+                        tx.execute(
+                            "INSERT OR REPLACE INTO watch_skipped_blocks (height, error, skipped) VALUES (?1, ?2, ?3)",
+                            params![height as i64, error, now],
+                        )?;
+                    }
+                    for delivery in deliveries {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO watch_deliveries (watch_id, height, txhash, url, payload, status, next_attempt, created)
+                            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+                            params![
+                                delivery.watch_id as i64,
+                                height as i64,
+                                delivery.txhash.to_string(),
+                                delivery.url,
+                                delivery.payload,
+                                DeliveryStatus::Pending.as_str(),
+                                now
+                            ],
+                        )?;
+                    }
+                    tx.execute(
+                        "INSERT OR REPLACE INTO watch_cursor (id, height) VALUES (0, ?1)",
+                        params![height as i64],
+                    )?;
+                    tx.commit()?;
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Counts a failed scan of the given height, returning how many times in a row it has failed.
+    fn record_scan_failure(&self, height: u64) -> u32 {
+        let mut failures = self.scan_failures.lock();
+        if failures.0 != height {
+            *failures = (height, 0);
+        }
+        failures.1 += 1;
+        failures.1
+    }
+
+    /// Finds the deliveries that a block causes, without queueing them.
+    async fn scan_block(
+        &self,
+        snap: &Snapshot,
+        block: Block,
+        watches: &[Watch],
+    ) -> anyhow::Result<Vec<NewDelivery>> {
+        let height = block.header.height;
+        let mut deliveries = vec![];
+        for tx in block.transactions.iter() {
+            let txhash = tx.hash_nosigs();
+            let inputs = try_join_all(tx.inputs.iter().map(|cid| {
+                let cid = *cid;
+                async move {
+                    let cdh = upstream(snap.get_coin_spent_here(cid))
+                        .await?
+                        .context("spent coin not found")?;
+                    anyhow::Ok((CoinDirection::Input, cid, cdh.coin_data))
+                }
+            }))
+            .await?;
+            let coins = inputs.into_iter().chain(
+                tx.outputs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, cd)| (CoinDirection::Output, tx.output_coinid(i as u8), cd.clone())),
+            );
+            let coins: Vec<_> = coins.collect();
+            for watch in watches {
+                let matches: Vec<WatchMatch> = coins
+                    .iter()
+                    .filter(|(_, _, cd)| watch.rule.matches(cd))
+                    .map(|(direction, coinid, cd)| WatchMatch {
+                        direction: *direction,
+                        coinid: *coinid,
+                        covhash: cd.covhash,
+                        denom: cd.denom,
+                        value: cd.value,
+                    })
+                    .collect();
+                if matches.is_empty() {
+                    continue;
+                }
+                let url = match watch
+                    .rule
+                    .webhook
+                    .clone()
+                    .or_else(|| self.default_webhook.clone())
+                {
+                    Some(url) => url,
+                    None => continue,
+                };
+                let event = WatchEvent {
+                    watch_id: watch.id,
+                    label: watch.rule.label.clone(),
+                    height,
+                    date: height_to_datetime(height),
+                    txhash,
+                    kind: tx.kind.to_string(),
+                    matches,
+                };
+                deliveries.push(NewDelivery {
+                    watch_id: watch.id,
+                    txhash,
+                    url,
+                    payload: serde_json::to_string(&event)?,
+                });
+            }
+        }
+        Ok(deliveries)
+    }
+
+    async fn deliver_pending(&self) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp();
+        let pending = self
+            .db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, url, payload, attempts FROM watch_deliveries
+                    WHERE status = ?1 AND next_attempt <= ?2
+                    ORDER BY id LIMIT 100",
+                )?;
+                let rows =
+                    stmt.query_map(params![DeliveryStatus::Pending.as_str(), now], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, i64>(3)? as u32,
+                        ))
+                    })?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await?;
+        stream::iter(pending.into_iter().map(anyhow::Ok))
+            .try_for_each_concurrent(DELIVERY_CONCURRENCY, |(id, url, payload, attempts)| {
+                self.deliver(id, url, payload, attempts, now)
+            })
+            .await
+    }
+
+    /// Makes one attempt at a pending delivery and records how it went.
+    async fn deliver(
+        &self,
+        id: i64,
+        url: String,
+        payload: String,
+        attempts: u32,
+        now: i64,
+    ) -> anyhow::Result<()> {
+        let attempts = attempts + 1;
+        let result = if Some(&url) == self.default_webhook.as_ref() {
+            post_json(&self.http, &url, payload).await
+        } else {
+            // the webhook's host may have been re-pointed since the watch was added
+            match check_public_webhook(&url).await {
+                Ok(()) => post_json(&self.public_http, &url, payload).await,
+                Err(err) => Err(err),
+            }
+        };
+        let (status, last_error, next_attempt) = match result {
+            Ok(()) => (DeliveryStatus::Delivered, None, now),
+            Err(err) => {
+                log::debug!("delivery {} to {} failed: {:?}", id, url, err);
+                let status = if attempts >= MAX_ATTEMPTS {
+                    DeliveryStatus::Failed
+                } else {
+                    DeliveryStatus::Pending
+                };
+                (status, Some(err.to_string()), now + retry_delay(attempts))
+            }
+        };
+        self.db
+            .run(move |conn| {
+                conn.execute(
+                    "UPDATE watch_deliveries SET status = ?1, attempts = ?2, last_error = ?3, next_attempt = ?4 WHERE id = ?5",
+                    params![status.as_str(), attempts as i64, last_error, next_attempt, id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// Wraps a plain connector so that it also speaks TLS to `https://` webhooks.
+fn with_tls<R>(mut http: HttpConnector<R>) -> HttpsConnector<HttpConnector<R>> {
+    http.enforce_http(false);
+    HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http)
+}
+
+/// A delivery found while scanning a block, not yet queued.
+struct NewDelivery {
+    watch_id: u64,
+    txhash: TxHash,
+    url: String,
+    payload: String,
+}
+
+/// Seconds to wait before the next attempt, after the given number of failed attempts.
+fn retry_delay(attempts: u32) -> i64 {
+    (RETRY_BASE_SECS << attempts.saturating_sub(1).min(16)).min(RETRY_MAX_SECS)
+}
+
+async fn post_json<C>(http: &Client<C>, url: &str, payload: String) -> anyhow::Result<()>
+where
+    C: rweb::hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let req = Request::post(url)
+        .header("content-type", "application/json")
+        .body(Body::from(payload))?;
+    let resp = tokio::time::timeout(DELIVERY_TIMEOUT, http.request(req))
+        .await
+        .context("webhook timed out")??;
+    if !resp.status().is_success() {
+        anyhow::bail!("webhook responded with {}", resp.status())
+    }
+    Ok(())
+}
+
+/// Whether an address is somewhere webhooks given by API users must not reach: loopback, private, link-local and other non-public ranges.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Checks that a webhook given by an API user only points at public addresses.
+async fn check_public_webhook(url: &str) -> anyhow::Result<()> {
+    let uri: Uri = url.parse().context("invalid webhook URL")?;
+    let host = uri.host().context("webhook URL has no host")?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+    let addrs: Vec<SocketAddr> =
+        tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port))
+            .await
+            .context("cannot resolve webhook host")?
+            .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| is_internal(addr.ip())) {
+        anyhow::bail!("webhooks cannot point at loopback, private or link-local addresses")
+    }
+    Ok(())
+}
+
+/// A DNS resolver that drops internal addresses, so that a webhook host cannot be re-pointed at one after it was checked. Hosts given as IP addresses skip resolution, so deliveries also check the URL itself.
+#[derive(Clone)]
+struct PublicResolver;
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "webhook host only resolves to internal addresses",
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+fn unix_to_datetime(secs: i64) -> chrono::DateTime<Utc> {
+    Utc.timestamp_opt(secs, 0).single().unwrap_or_else(Utc::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::Filter;
+
+    use super::*;
+
+    fn coin(covhash: Address, denom: Denom, value: u128) -> CoinData {
+        CoinData {
+            covhash,
+            value: CoinValue(value),
+            denom,
+            additional_data: Default::default(),
+        }
+    }
+
+    #[test]
+    fn rule_matches_same_coin() {
+        let alice = Address(tmelcrypt::hash_single(b"alice"));
+        let bob = Address(tmelcrypt::hash_single(b"bob"));
+        let rule = WatchRule {
+            address: Some(alice),
+            denom: Some(Denom::Mel),
+            min_value: Some(CoinValue(100)),
+            label: None,
+            webhook: None,
+        };
+        assert!(rule.matches(&coin(alice, Denom::Mel, 100)));
+        assert!(!rule.matches(&coin(alice, Denom::Mel, 99)));
+        assert!(!rule.matches(&coin(alice, Denom::Sym, 1000)));
+        assert!(!rule.matches(&coin(bob, Denom::Mel, 1000)));
+
+        let any_mel = WatchRule {
+            address: None,
+            denom: Some(Denom::Mel),
+            min_value: None,
+            label: None,
+            webhook: None,
+        };
+        assert!(any_mel.matches(&coin(bob, Denom::Mel, 0)));
+    }
+
+    #[test]
+    fn retries_back_off() {
+        assert_eq!(retry_delay(1), RETRY_BASE_SECS);
+        assert_eq!(retry_delay(2), RETRY_BASE_SECS * 2);
+        assert_eq!(retry_delay(4), RETRY_BASE_SECS * 8);
+        assert_eq!(
+            retry_delay(MAX_ATTEMPTS),
+            RETRY_MAX_SECS.min(RETRY_BASE_SECS << 7)
+        );
+        assert_eq!(retry_delay(100), RETRY_MAX_SECS);
+    }
+
+    #[test]
+    fn delivers_and_retries() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // a receiver that fails the first request to /flaky
+            let received = Arc::new(Mutex::new(vec![]));
+            let log = received.clone();
+            let receiver = warp::post()
+                .and(warp::path::param::<String>())
+                .and(warp::header::<String>("content-type"))
+                .and(warp::body::bytes())
+                .map(move |path: String, content_type: String, body: rweb::hyper::body::Bytes| {
+                    let mut log = log.lock();
+                    log.push((path, content_type, String::from_utf8(body.to_vec()).unwrap()));
+                    let status = if log.len() == 1 { 500 } else { 200 };
+                    warp::reply::with_status(
+                        warp::reply(),
+                        warp::http::StatusCode::from_u16(status).unwrap(),
+                    )
+                });
+            let (addr, server) = warp::serve(receiver).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let flaky = format!("http://{}/flaky", addr);
+            // not the default webhook, so it must never reach the receiver
+            let internal = format!("http://{}/internal", addr);
+
+            let watchlist = Watchlist::new(LocalDb::open(None).unwrap(), Some(flaky.clone())).unwrap();
+            let txhash = TxHash(tmelcrypt::hash_single(b"tx"));
+            let event = WatchEvent {
+                watch_id: 1,
+                label: Some("test".into()),
+                height: BlockHeight(10),
+                date: height_to_datetime(BlockHeight(10)),
+                txhash,
+                kind: "Normal".into(),
+                matches: vec![WatchMatch {
+                    direction: CoinDirection::Output,
+                    coinid: CoinID { txhash, index: 0 },
+                    covhash: Address(tmelcrypt::hash_single(b"alice")),
+                    denom: Denom::Mel,
+                    value: CoinValue(100),
+                }],
+            };
+            let payload = serde_json::to_string(&event).unwrap();
+            let queued = payload.clone();
+            let queued_internal = internal.clone();
+            watchlist
+                .db
+                .run(move |conn| {
+                    conn.execute(
+                        "INSERT INTO watch_deliveries (watch_id, height, txhash, url, payload, status, attempts, next_attempt, created)
+                        VALUES (1, 10, ?1, ?2, ?3, 'pending', 0, 0, 0), (2, 10, ?1, ?4, ?3, 'pending', ?5, 0, 0)",
+                        params![txhash.to_string(), flaky, queued, queued_internal, MAX_ATTEMPTS as i64 - 1],
+                    )?;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            let status_of = |watch_id: u64| {
+                let watchlist = watchlist.clone();
+                async move {
+                    let deliveries = watchlist.list_deliveries(Some(watch_id), 10).await.unwrap();
+                    let delivery = deliveries[0].clone();
+                    (delivery.status, delivery.attempts, delivery.last_error)
+                }
+            };
+
+            watchlist.deliver_pending().await.unwrap();
+            {
+                let received = received.lock();
+                assert_eq!(received.len(), 1);
+                let (path, content_type, body) = &received[0];
+                assert_eq!(path, "flaky");
+                assert_eq!(content_type, "application/json");
+                assert_eq!(body, &payload);
+                let body: serde_json::Value = serde_json::from_str(body).unwrap();
+                assert_eq!(body["label"], "test");
+                assert_eq!(body["matches"][0]["direction"], "output");
+            }
+            let (status, attempts, last_error) = status_of(1).await;
+            assert_eq!((status, attempts), (DeliveryStatus::Pending, 1));
+            assert!(last_error.unwrap().contains("500"));
+            // the last attempt at a webhook pointing at an internal address gives up on it
+            let (status, attempts, last_error) = status_of(2).await;
+            assert_eq!((status, attempts), (DeliveryStatus::Failed, MAX_ATTEMPTS));
+            assert!(last_error.is_some());
+
+            // the retry is not due yet
+            watchlist.deliver_pending().await.unwrap();
+            assert_eq!(received.lock().len(), 1);
+
+            watchlist
+                .db
+                .run(|conn| {
+                    conn.execute("UPDATE watch_deliveries SET next_attempt = 0 WHERE watch_id = 1", [])?;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            watchlist.deliver_pending().await.unwrap();
+            {
+                let received = received.lock();
+                assert_eq!(received.len(), 2);
+                assert_eq!(received[1].2, payload);
+                assert!(received.iter().all(|(path, ..)| path == "flaky"));
+            }
+            let (status, attempts, _) = status_of(1).await;
+            assert_eq!((status, attempts), (DeliveryStatus::Delivered, 2));
+        });
+    }
+
+    #[test]
+    fn internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ]
+        .iter()
+        {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "2606:4700:4700::1111"].iter() {
+            assert!(!is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
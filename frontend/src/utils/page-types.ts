@@ -62,7 +62,65 @@ export interface TransactionResponse {
 	gross_gain: Vec<MicroUnit>;
 	weight: number;
 	kind: string;
-	covenants: Vec<[String, Vec<String>]>;
+	covenants: Vec<Disassembly>;
+}
+
+export interface Disassembly {
+	address: string;
+	size: number;
+	weight: number;
+	ops: Vec<any>;
+	flow: Vec<any>;
+	pretty: Vec<string>;
+	class?: CovenantClass;
+}
+
+export interface CovenantClass {
+	type:
+		| 'ed25519'
+		| 'ed25519_legacy'
+		| 'multisig'
+		| 'timelock'
+		| 'burn'
+		| 'custom'
+		| 'unrevealed';
+	pubkey?: string;
+	pubkeys?: string[];
+	threshold?: number;
+	unlock_height?: number;
+}
+
+export const describeCovenantClass = (c: CovenantClass): string => {
+	switch (c.type) {
+		case 'ed25519':
+			return `Single-signature (${c.pubkey})`;
+		case 'ed25519_legacy':
+			return `Single-signature, legacy (${c.pubkey})`;
+		case 'multisig':
+			return `${c.threshold}-of-${c.pubkeys.length} multisig`;
+		case 'timelock':
+			return `Timelocked until block ${c.unlock_height} (${c.pubkey})`;
+		case 'burn':
+			return 'Burn address';
+		case 'custom':
+			return 'Custom covenant';
+		case 'unrevealed':
+			return 'Unknown (covenant never revealed)';
+	}
+};
+
+export interface Reveal {
+	height: u64;
+	txhash: TxHash;
+}
+
+export interface CovenantSummary {
+	bytes: string;
+	class: CovenantClass;
+	disassembly: Disassembly;
+	first_reveal: Reveal;
+	reveal_count: number;
+	reveals: Vec<Reveal>;
 }
 
 export interface BreadCrumb {